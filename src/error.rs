@@ -8,11 +8,31 @@ pub enum ShmemError {
     LinkExists,
     LinkOpenFailed(std::io::Error),
     LinkReadFailed(std::io::Error),
-    LinkDoesNotExist,
+    LinkDoesNotExist(std::path::PathBuf),
     MappingIdExists,
     MapCreateFailed(u32),
     MapOpenFailed(u32),
     UnknownOsError(u32),
+    RangeOutOfBounds,
+    MapFlushFailed(u32),
+    LinkPathEmpty,
+    MapPermissionDenied,
+    InvalidOsId,
+    MemoryLockLimit,
+    LinkRemoveFailed(std::io::Error),
+    MapCloseFailed(u32),
+    AddressUnavailable,
+    LinkCorrupt,
+    OutOfMemory(usize),
+    SizeOverflow(usize),
+    Timeout,
+    SizeTooSmall(usize),
+    UnalignedOffset(usize),
+    AnonymousFlink,
+    NumaUnsupported,
+    LinkMetadataInvalid,
+    LinkMetadataCorrupt,
+    NumaNodeInvalid(u32),
 }
 
 impl std::fmt::Display for ShmemError {
@@ -26,11 +46,31 @@ impl std::fmt::Display for ShmemError {
             ShmemError::LinkExists => f.write_str("Shared memory link already exists"),
             ShmemError::LinkOpenFailed(err) => write!(f, "Opening the link file failed, {err}"),
             ShmemError::LinkReadFailed(err) => write!(f, "Reading the link file failed, {err}"),
-            ShmemError::LinkDoesNotExist => f.write_str("Requested link file does not exist"),
+            ShmemError::LinkDoesNotExist(path) => write!(f, "Requested link file does not exist at {}, relative link paths are resolved against the current directory", path.display()),
             ShmemError::MappingIdExists => f.write_str("Shared memory OS specific ID already exists"),
             ShmemError::MapCreateFailed(err) => write!(f, "Creating the shared memory failed, os error {err}"),
             ShmemError::MapOpenFailed(err) => write!(f, "Opening the shared memory failed, os error {err}"),
             ShmemError::UnknownOsError(err) => write!(f, "An unexpected OS error occurred, os error {err}"),
+            ShmemError::RangeOutOfBounds => f.write_str("The requested range does not fit inside the mapping"),
+            ShmemError::MapFlushFailed(err) => write!(f, "Flushing the shared memory failed, os error {err}"),
+            ShmemError::LinkPathEmpty => f.write_str("The file link path is empty"),
+            ShmemError::MapPermissionDenied => f.write_str("Not allowed to access the shared memory, check the permissions of its OS object"),
+            ShmemError::InvalidOsId => f.write_str("The os_id is not a valid shared memory identifier on this platform"),
+            ShmemError::MemoryLockLimit => f.write_str("Locking the shared memory in RAM would exceed the process limit"),
+            ShmemError::LinkRemoveFailed(err) => write!(f, "Deleting the link file failed, {err}"),
+            ShmemError::MapCloseFailed(err) => write!(f, "Closing the shared memory failed, os error {err}"),
+            ShmemError::AddressUnavailable => f.write_str("The requested address is not available to map the shared memory"),
+            ShmemError::LinkCorrupt => f.write_str("The link file is empty or does not contain valid UTF-8"),
+            ShmemError::OutOfMemory(size) => write!(f, "Not enough memory or space for a shared memory mapping of {size} bytes"),
+            ShmemError::SizeOverflow(size) => write!(f, "The requested size of {size} bytes is too large for a mapping"),
+            ShmemError::Timeout => f.write_str("Timed out waiting for the shared memory"),
+            ShmemError::SizeTooSmall(size) => write!(f, "The shared memory is only {size} bytes, less than the requested minimum size"),
+            ShmemError::UnalignedOffset(offset) => write!(f, "The offset {offset} is not a multiple of the page size (of the allocation granularity on Windows)"),
+            ShmemError::AnonymousFlink => f.write_str("Anonymous mappings have no name a file link could point to"),
+            ShmemError::NumaUnsupported => f.write_str("NUMA memory policies are not supported or not permitted on this system"),
+            ShmemError::LinkMetadataInvalid => f.write_str("Link metadata keys and values must be single lines and keys can't contain '='"),
+            ShmemError::LinkMetadataCorrupt => f.write_str("The link file contains a metadata line that is not a key=value pair"),
+            ShmemError::NumaNodeInvalid(node) => write!(f, "NUMA node {node} is out of range, Linux supports at most 1024 nodes"),
         }
     }
 }
@@ -42,6 +82,7 @@ impl std::error::Error for ShmemError {
             ShmemError::LinkWriteFailed(err) => Some(err),
             ShmemError::LinkOpenFailed(err) => Some(err),
             ShmemError::LinkReadFailed(err) => Some(err),
+            ShmemError::LinkRemoveFailed(err) => Some(err),
             _ => None,
         }
     }
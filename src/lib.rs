@@ -2,11 +2,16 @@
 //!
 //! For help on how to get started, take a look at the [examples](https://github.com/elast0ny/shared_memory-rs/tree/master/examples) !
 
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs::{File, OpenOptions};
 use std::io::{ErrorKind, Read, Write};
 
 use std::fs::remove_file;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::time::{Duration, Instant};
 
 use cfg_if::cfg_if;
 
@@ -44,24 +49,38 @@ cfg_if! {
     }
 }
 
-#[derive(Clone, Default)]
+/// How the pages of an opened mapping can be accessed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum MapAccess {
+    #[default]
+    ReadWrite,
+    ReadOnly,
+    CopyOnWrite,
+}
+
+#[derive(Clone, Debug, Default)]
 /// Struct used to configure different parameters before creating a shared memory mapping
 pub struct ShmemConf {
     owner: bool,
     os_id: Option<String>,
     overwrite_flink: bool,
+    replace_stale_flink: bool,
     flink_path: Option<PathBuf>,
+    flink_metadata: Vec<(String, String)>,
     size: usize,
+    min_size: usize,
+    access: MapAccess,
+    address: Option<usize>,
+    window: Option<(usize, usize)>,
+    wipe_on_drop: bool,
+    open_retries: Option<(usize, Duration)>,
+    prefault: bool,
     ext: os_impl::ShmemConfExt,
 }
 impl Drop for ShmemConf {
     fn drop(&mut self) {
-        // Delete the flink if we are the owner of the mapping
-        if self.owner {
-            if let Some(flink_path) = self.flink_path.as_ref() {
-                debug!("Deleting file link {}", flink_path.to_string_lossy());
-                let _ = remove_file(flink_path);
-            }
+        if let Err(_e) = self.remove_flink() {
+            debug!("Failed to delete file link : {}", _e);
         }
     }
 }
@@ -74,6 +93,9 @@ impl ShmemConf {
     /// Provide a specific os identifier for the mapping
     ///
     /// When not specified, a randomly generated identifier will be used
+    ///
+    /// On macOS, identifiers are limited to 31 bytes (`PSHMNAMLEN`) including the leading `/`. Generated
+    /// identifiers always fit, `create()` and `open()` return `ShmemError::InvalidOsId` for longer ones.
     pub fn os_id<S: AsRef<str>>(mut self, os_id: S) -> Self {
         self.os_id = Some(String::from(os_id.as_ref()));
         self
@@ -85,30 +107,272 @@ impl ShmemConf {
         self
     }
 
+    /// If set to true, `create()` replaces a file link whose mapping doesn't exist anymore
+    ///
+    /// This reclaims the link left behind by an owner that crashed, without a separate cleanup step. Links to
+    /// a live mapping are never replaced and `create()` still fails with `ShmemError::LinkExists`. Note that on
+    /// unix, the OS object of an owner that crashed stays alive until it is deleted, see [`ShmemConf::status`].
+    ///
+    /// The link is read again right before deleting it and kept if it changed. This is not atomic though : a
+    /// process publishing a new link between that last check and the deletion loses its link, so concurrent
+    /// creators of the same flink should not rely on this. An empty or corrupt link is only replaced if it
+    /// hasn't changed 100ms later, on filesystems without hard links it may still be in the middle of being
+    /// written by a slow creator.
+    pub fn replace_stale_flink(mut self, replace_stale_flink: bool) -> Self {
+        self.replace_stale_flink = replace_stale_flink;
+        self
+    }
+
     /// Create the shared memory mapping with a file link
     ///
     /// This creates a file on disk that contains the unique os_id for the mapping.
     /// This can be useful when application want to rely on filesystems to share mappings
+    ///
+    /// Relative paths are resolved against the current directory when calling `create()` or `open()`, processes
+    /// running from different directories must use absolute paths. A missing link is reported as
+    /// `ShmemError::LinkDoesNotExist` with the path that was looked up.
+    ///
+    /// Note that the shared memory itself is always local to the machine. Putting the file link on a
+    /// network filesystem (NFS, SMB, etc...) only shares the os_id and a node crashing before its owner
+    /// dropped the mapping leaves a stale link behind, which `open()` reports as a `MapOpenFailed` error.
     pub fn flink<S: AsRef<Path>>(mut self, path: S) -> Self {
         self.flink_path = Some(PathBuf::from(path.as_ref()));
         self
     }
 
+    /// Stores `key=value` in the file link written by `create()`, see [`ShmemConf::read_flink_metadata`]
+    ///
+    /// The link then holds the os_id on its first line followed by one `key=value` line per entry. Keys can't
+    /// contain `=` and neither keys nor values can contain line breaks, `create()` fails with
+    /// `ShmemError::LinkMetadataInvalid` otherwise. Links without metadata keep the bare os_id format.
+    pub fn flink_metadata<K: AsRef<str>, V: AsRef<str>>(mut self, key: K, value: V) -> Self {
+        self.flink_metadata
+            .push((key.as_ref().to_string(), value.as_ref().to_string()));
+        self
+    }
+
     /// Sets the size of the mapping that will be used in `create()`
+    ///
+    /// The size is rounded up to a multiple of the page size, see [`Shmem::len`]. Sizes above `isize::MAX` are
+    /// rejected with `ShmemError::SizeOverflow`, sizes the system can't provide with `ShmemError::OutOfMemory`.
+    ///
+    /// Pages are never committed up front :
+    /// - Linux/FreeBSD/macOS : the `shm_open()` object is sparse, physical pages are only allocated on first touch
+    /// - Windows : the mapping is backed by a temporary file (not the pagefile) so it does not count against the
+    ///   commit limit. The file is extended to `size` but its pages are only brought into memory when accessed
+    ///
+    /// Very large mostly empty mappings are therefore cheap, but touching a page the system can no longer back
+    /// (shm filesystem or disk full) raises `SIGBUS` on unix and `EXCEPTION_IN_PAGE_ERROR` on Windows.
     pub fn size(mut self, size: usize) -> Self {
         self.size = size;
         self
     }
 
+    /// Makes `open()` fail with `ShmemError::SizeTooSmall` when the mapping is smaller than `min_size`
+    ///
+    /// `open()` always maps the size chosen by the creator, this catches creators and openers that disagree
+    /// on the size before anything reads past the end of the mapping. This is ignored by `create()`.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Maps the memory at `addr` instead of letting the OS pick an address
+    ///
+    /// When every process maps the memory at the same address, it can hold absolute pointers into itself.
+    /// `addr` must be a multiple of the page size (of the allocation granularity, usually 64KB, on Windows).
+    /// If anything is already mapped there, `create()` and `open()` fail with `ShmemError::AddressUnavailable`
+    /// instead of replacing it.
+    pub fn fixed_address(mut self, addr: usize) -> Self {
+        self.address = Some(addr);
+        self
+    }
+
+    /// Makes `open()` only map `len` bytes of the shared memory, starting `offset` bytes into it
+    ///
+    /// This is useful to work on a small part of a very large mapping without reserving address space for
+    /// all of it. `offset` must be a multiple of the page size (of the allocation granularity, usually 64KB,
+    /// on Windows), `open()` fails with `ShmemError::UnalignedOffset` otherwise. A window that doesn't fit in
+    /// the mapping is rejected with `ShmemError::RangeOutOfBounds` and an empty one with `ShmemError::MapSizeZero`.
+    /// This is ignored by `create()`.
+    pub fn window(mut self, offset: usize, len: usize) -> Self {
+        self.window = Some((offset, len));
+        self
+    }
+
+    /// If set to true, the owner overwrites the memory with zeros before dropping the mapping
+    ///
+    /// See [`Shmem::zeroize`]. This is useful for mappings that hold secrets, read-only mappings are never wiped.
+    pub fn wipe_on_drop(mut self, wipe_on_drop: bool) -> Self {
+        self.wipe_on_drop = wipe_on_drop;
+        self
+    }
+
+    /// Makes `open()` retry up to `retries` times, waiting `delay` in between, while the mapping isn't ready
+    ///
+    /// This covers starting before the creator : a missing or empty flink and a mapping that doesn't exist
+    /// yet are retried, permanent errors like `ShmemError::MapPermissionDenied` are returned right away.
+    /// By default, `open()` only retries 5 times 50ms when the os_id read from a flink fails to open.
+    pub fn open_retries(mut self, retries: usize, delay: Duration) -> Self {
+        self.open_retries = Some((retries, delay));
+        self
+    }
+
+    /// If set to true, `create()` and `open()` fault in every page of the mapping, see [`Shmem::prefault`]
+    pub fn prefault(mut self, prefault: bool) -> Self {
+        self.prefault = prefault;
+        self
+    }
+
+    /// If set to true, `open()` maps the memory without write access
+    ///
+    /// Pages are mapped with `PROT_READ` on unix and `FILE_MAP_READ` on Windows, so only read access to the
+    /// OS object is required. Writing through such a mapping crashes the process. This is ignored by `create()`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.access = if read_only {
+            MapAccess::ReadOnly
+        } else {
+            MapAccess::ReadWrite
+        };
+        self
+    }
+
+    /// If set to true, `open()` maps the memory privately : writes are only visible to this mapping
+    ///
+    /// Pages are copied on first write (`MAP_PRIVATE` on unix, `FILE_MAP_COPY` on Windows) so other processes
+    /// and the shared memory itself never see the changes, which are discarded when the mapping is dropped.
+    /// Only read access to the OS object is required. This is ignored by `create()`.
+    pub fn copy_on_write(mut self, copy_on_write: bool) -> Self {
+        self.access = if copy_on_write {
+            MapAccess::CopyOnWrite
+        } else {
+            MapAccess::ReadWrite
+        };
+        self
+    }
+
+    /// Deletes the flink if we are the owner of the mapping
+    fn remove_flink(&mut self) -> Result<(), ShmemError> {
+        if !self.owner {
+            return Ok(());
+        }
+        // Only try once, even if it fails
+        self.owner = false;
+        if let Some(flink_path) = self.flink_path.as_ref() {
+            debug!("Deleting file link {}", flink_path.to_string_lossy());
+            remove_file(flink_path).map_err(ShmemError::LinkRemoveFailed)?;
+        }
+        Ok(())
+    }
+
+    /// Validates the flink path and makes it absolute
+    ///
+    /// Relative paths are resolved against the current directory once, so the mapping keeps refering to
+    /// the same link file if the process changes directory later on.
+    fn resolve_flink_path(&mut self) -> Result<(), ShmemError> {
+        self.check_flink_path()?;
+        let flink_path = match self.flink_path.as_mut() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        if flink_path.is_relative() {
+            match std::env::current_dir() {
+                Ok(cur_dir) => *flink_path = cur_dir.join(&flink_path),
+                Err(e) => {
+                    return Err(ShmemError::UnknownOsError(
+                        e.raw_os_error().unwrap_or(0) as _
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rejects a blank flink path, without resolving it
+    fn check_flink_path(&self) -> Result<(), ShmemError> {
+        match self.flink_path.as_ref() {
+            Some(p) if p.to_string_lossy().trim().is_empty() => Err(ShmemError::LinkPathEmpty),
+            _ => Ok(()),
+        }
+    }
+
+    /// Deletes the flink at `flink_path` if its mapping doesn't exist, fails with `LinkExists` otherwise
+    fn remove_stale_flink(&self, flink_path: &Path) -> Result<(), ShmemError> {
+        let contents = match std::fs::read(flink_path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(_) => return Err(ShmemError::LinkExists),
+        };
+        let unique_id = std::str::from_utf8(&contents)
+            .ok()
+            .and_then(|s| parse_flink(s).ok());
+        let stale = match unique_id {
+            Some(unique_id) => !os_impl::mapping_exists(&unique_id, &self.ext)?,
+            // Without hard links, the flink is written in place and another creator may not have written the
+            // os_id yet. Only a link that stays corrupt is stale
+            None => {
+                std::thread::sleep(Duration::from_millis(100));
+                matches!(std::fs::read(flink_path), Ok(c) if c == contents)
+            }
+        };
+        if !stale {
+            return Err(ShmemError::LinkExists);
+        }
+
+        // Only delete the link if it wasn't replaced while its mapping was checked. A link published between
+        // this last read and remove_file() is still lost
+        match std::fs::read(flink_path) {
+            Ok(c) if c == contents => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            _ => return Err(ShmemError::LinkExists),
+        }
+        debug!("Deleting stale file link {}", flink_path.to_string_lossy());
+        match remove_file(flink_path) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(ShmemError::LinkRemoveFailed(e)),
+        }
+    }
+
     /// Create a new mapping using the current configuration
+    ///
+    /// The memory of a newly created mapping is always zero-filled. When several processes race to create
+    /// the same os_id or flink, exactly one of them succeeds and the others get `ShmemError::MappingIdExists`
+    /// or `ShmemError::LinkExists`, so a successful `create()` (or `Shmem::is_owner()`) tells the caller it
+    /// is responsible for initializing the contents.
     pub fn create(mut self) -> Result<Shmem, ShmemError> {
         if self.size == 0 {
             return Err(ShmemError::MapSizeZero);
         }
+        // Slices of the mapping can't be larger than isize::MAX
+        if self.size > isize::MAX as usize {
+            return Err(ShmemError::SizeOverflow(self.size));
+        }
+
+        self.resolve_flink_path()?;
+        if self.flink_path.is_some() && os_impl::is_anonymous(&self.ext) {
+            return Err(ShmemError::AnonymousFlink);
+        }
+        self.access = MapAccess::ReadWrite;
+        self.window = None;
+
+        let is_line = |s: &str| !s.contains(['\n', '\r']);
+        if !self
+            .flink_metadata
+            .iter()
+            .all(|(k, v)| is_line(k) && !k.contains('=') && is_line(v))
+        {
+            return Err(ShmemError::LinkMetadataInvalid);
+        }
 
         if let Some(ref flink_path) = self.flink_path {
             if !self.overwrite_flink && flink_path.is_file() {
-                return Err(ShmemError::LinkExists);
+                if !self.replace_stale_flink {
+                    return Err(ShmemError::LinkExists);
+                }
+                self.remove_stale_flink(flink_path)?;
             }
         }
 
@@ -118,7 +382,7 @@ impl ShmemConf {
                 // Generate random ID until one works
                 loop {
                     let cur_id = format!("/shmem_{:X}", rand::random::<u64>());
-                    match os_impl::create_mapping(&cur_id, self.size) {
+                    match os_impl::create_mapping(&cur_id, self.size, self.address, &self.ext) {
                         Err(ShmemError::MappingIdExists) => continue,
                         Ok(m) => break m,
                         Err(e) => {
@@ -127,33 +391,40 @@ impl ShmemConf {
                     };
                 }
             }
-            Some(ref specific_id) => os_impl::create_mapping(specific_id, self.size)?,
+            Some(ref specific_id) => {
+                os_impl::create_mapping(specific_id, self.size, self.address, &self.ext)?
+            }
         };
         debug!("Created shared memory mapping '{}'", mapping.unique_id);
 
         // Create flink
         if let Some(ref flink_path) = self.flink_path {
             debug!("Creating file link that points to mapping");
-            let mut open_options: OpenOptions = OpenOptions::new();
-            open_options.write(true);
-
-            if self.overwrite_flink {
-                open_options.create(true).truncate(true);
-            } else {
-                open_options.create_new(true);
+            // Write the id to a temporary file next to the link and only then publish it under its final
+            // name, this way openers never read a partially written link
+            let tmp_path = flink_tmp_path(flink_path, &mapping.unique_id);
+            let mut contents = mapping.unique_id.clone();
+            for (key, value) in self.flink_metadata.iter() {
+                contents.push_str(&format!("\n{key}={value}"));
             }
+            write_new_flink(&tmp_path, &contents, &self.ext)?;
 
-            match open_options.open(flink_path) {
-                Ok(mut f) => {
-                    // write the shmem uid asap
-                    if let Err(e) = f.write(mapping.unique_id.as_bytes()) {
-                        let _ = std::fs::remove_file(flink_path);
-                        return Err(ShmemError::LinkWriteFailed(e));
-                    }
-                }
+            let res = if self.overwrite_flink {
+                std::fs::rename(&tmp_path, flink_path)
+            } else {
+                // Unlike rename(), creating a hard link fails if the flink already exists
+                std::fs::hard_link(&tmp_path, flink_path)
+            };
+            let _ = remove_file(&tmp_path);
+            match res {
+                Ok(_) => {}
                 Err(e) if e.kind() == ErrorKind::AlreadyExists => {
                     return Err(ShmemError::LinkExists)
                 }
+                // Some filesystems (FAT, SMB, FUSE, ...) have no hard links, write the flink in place instead
+                Err(_) if !self.overwrite_flink => {
+                    write_new_flink(flink_path, &contents, &self.ext)?
+                }
                 Err(e) => return Err(ShmemError::LinkCreateFailed(e)),
             }
 
@@ -164,12 +435,17 @@ impl ShmemConf {
             );
         }
 
+        if self.prefault {
+            mapping.prefault(0, mapping.map_size)?;
+        }
+
         self.owner = true;
         self.size = mapping.map_size;
 
         Ok(Shmem {
             config: self,
             mapping,
+            on_unmap: Vec::new(),
         })
     }
 
@@ -180,56 +456,275 @@ impl ShmemConf {
             debug!("Open called with no file link or unique id...");
             return Err(ShmemError::NoLinkOrOsId);
         }
+        // Windows would map everything past the offset
+        if matches!(self.window, Some((_, 0))) {
+            return Err(ShmemError::MapSizeZero);
+        }
+
+        self.resolve_flink_path()?;
 
-        let mut flink_uid = String::new();
+        let custom_retries = self.open_retries.is_some();
+        let (max_retries, delay) = self.open_retries.unwrap_or((5, Duration::from_millis(50)));
         let mut retry = 0;
         loop {
-            let unique_id = if let Some(ref unique_id) = self.os_id {
-                retry = 5;
-                unique_id.as_str()
-            } else {
-                let flink_path = self.flink_path.as_ref().unwrap();
-                debug!(
-                    "Open shared memory from file link {}",
-                    flink_path.to_string_lossy()
-                );
-                let mut f = match File::open(flink_path) {
-                    Ok(f) => f,
-                    Err(e) => return Err(ShmemError::LinkOpenFailed(e)),
-                };
-                flink_uid.clear();
-                if let Err(e) = f.read_to_string(&mut flink_uid) {
-                    return Err(ShmemError::LinkReadFailed(e));
+            let res = match self.os_id {
+                Some(ref unique_id) => Ok(unique_id.clone()),
+                None => {
+                    let flink_path = self.flink_path.as_ref().unwrap();
+                    debug!(
+                        "Open shared memory from file link {}",
+                        flink_path.to_string_lossy()
+                    );
+                    read_flink(flink_path)
                 }
-                flink_uid.as_str()
-            };
+            }
+            .and_then(|unique_id| {
+                os_impl::open_mapping(
+                    &unique_id,
+                    self.size,
+                    self.access,
+                    self.address,
+                    self.window,
+                    &self.ext,
+                )
+            });
 
-            match os_impl::open_mapping(unique_id, self.size, &self.ext) {
+            let e = match res {
                 Ok(m) => {
+                    if m.map_size < self.min_size {
+                        return Err(ShmemError::SizeTooSmall(m.map_size));
+                    }
+                    if self.prefault {
+                        m.prefault(0, m.map_size)?;
+                    }
                     self.size = m.map_size;
                     self.owner = false;
 
                     return Ok(Shmem {
                         config: self,
                         mapping: m,
+                        on_unmap: Vec::new(),
                     });
                 }
-                // If we got this failing os_id from the flink, try again in case the shmem owner didnt write the full
-                // unique_id to the file
-                Err(ShmemError::MapOpenFailed(_)) if self.os_id.is_none() && retry < 5 => {
-                    retry += 1;
-                    std::thread::sleep(std::time::Duration::from_millis(50));
-                }
+                Err(e) => e,
+            };
+
+            // By default, only retry when a mapping found through the flink fails to open, in case the shmem
+            // owner didnt write the full unique_id to the file. Custom retries also wait for the flink and the
+            // mapping to be created.
+            let transient = match e {
+                ShmemError::MapOpenFailed(_) => custom_retries || self.os_id.is_none(),
+                ShmemError::LinkCorrupt => custom_retries,
+                ShmemError::LinkDoesNotExist(_) => custom_retries,
+                _ => false,
+            };
+            if !transient || retry >= max_retries {
+                return Err(e);
+            }
+            retry += 1;
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Tells whether the mapping this configuration refers to exists, without mapping it
+    ///
+    /// The os_id is read from the flink when one is set, the configured os_id is only checked when the flink
+    /// is missing. Note that on unix, the OS object of an owner that crashed stays alive until it is deleted.
+    pub fn status(&self) -> Result<ShmemStatus, ShmemError> {
+        self.check_flink_path()?;
+        let unique_id = match (self.flink_path.as_ref(), self.os_id.as_ref()) {
+            (Some(flink_path), os_id) => match read_flink(flink_path) {
+                Ok(unique_id) => unique_id,
+                // Owners delete their flink before the mapping, check the os_id when we know it
+                Err(ShmemError::LinkDoesNotExist(_)) => match os_id {
+                    Some(os_id) if os_impl::mapping_exists(os_id, &self.ext)? => {
+                        return Ok(ShmemStatus::Live)
+                    }
+                    _ => return Ok(ShmemStatus::Missing),
+                },
                 Err(e) => return Err(e),
+            },
+            (None, Some(os_id)) => os_id.clone(),
+            (None, None) => return Err(ShmemError::NoLinkOrOsId),
+        };
+
+        Ok(match os_impl::mapping_exists(&unique_id, &self.ext)? {
+            true => ShmemStatus::Live,
+            false if self.flink_path.is_some() => ShmemStatus::Stale,
+            false => ShmemStatus::Missing,
+        })
+    }
+
+    /// Returns the metadata stored in the flink with [`ShmemConf::flink_metadata`], without mapping the memory
+    ///
+    /// Links holding only an os_id have no metadata. Keys and values are returned exactly as they were
+    /// written, lines that aren't `key=value` pairs are reported as `ShmemError::LinkMetadataCorrupt`.
+    pub fn read_flink_metadata(&self) -> Result<BTreeMap<String, String>, ShmemError> {
+        self.check_flink_path()?;
+        let flink_path = match self.flink_path.as_ref() {
+            Some(p) => p,
+            None => return Err(ShmemError::NoLinkOrOsId),
+        };
+
+        let contents = read_flink_contents(flink_path)?;
+        let mut metadata = BTreeMap::new();
+        // Only the line breaks are stripped, values may end in whitespace
+        for line in contents.lines().skip(1).filter(|l| !l.is_empty()) {
+            match line.split_once('=') {
+                Some((key, value)) => metadata.insert(key.to_string(), value.to_string()),
+                None => return Err(ShmemError::LinkMetadataCorrupt),
+            };
+        }
+        Ok(metadata)
+    }
+
+    /// Blocks until the mapping and its flink are both gone, polling them
+    ///
+    /// This lets a supervisor wait for an owner to finish tearing down a mapping before starting a new one.
+    /// The mapping checked is the configured os_id or the last one read from the flink, so a flink that is
+    /// already missing on the first check with no os_id configured counts as removed.
+    /// Returns `ShmemError::Timeout` if the mapping is still there after `timeout`, a flink left behind by an
+    /// owner that crashed keeps this waiting until it is deleted.
+    pub fn wait_until_removed(&self, timeout: Duration) -> Result<(), ShmemError> {
+        const POLL_INTERVAL: Duration = Duration::from_millis(10);
+        let deadline = Instant::now() + timeout;
+        if self.flink_path.is_none() && self.os_id.is_none() {
+            return Err(ShmemError::NoLinkOrOsId);
+        }
+        self.check_flink_path()?;
+
+        let mut unique_id = self.os_id.clone();
+        loop {
+            let flink_exists = match self.flink_path.as_ref() {
+                Some(flink_path) => match read_flink(flink_path) {
+                    Ok(id) => {
+                        unique_id = Some(id);
+                        true
+                    }
+                    Err(ShmemError::LinkDoesNotExist(_)) => false,
+                    Err(ShmemError::LinkCorrupt) => true,
+                    Err(e) => return Err(e),
+                },
+                None => false,
+            };
+            // Owners delete their flink before the mapping
+            let mapping_exists = match unique_id.as_ref() {
+                Some(id) => os_impl::mapping_exists(id, &self.ext)?,
+                None => false,
+            };
+            if !flink_exists && !mapping_exists {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ShmemError::Timeout);
             }
+            std::thread::sleep(POLL_INTERVAL.min(deadline - now));
         }
     }
 }
 
+/// State of the mapping a [`ShmemConf`] refers to, see [`ShmemConf::status`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShmemStatus {
+    /// The mapping doesn't exist, or its flink is missing and no os_id was configured to check it
+    Missing,
+    /// The flink exists but the mapping it points to does not
+    Stale,
+    /// The mapping exists
+    Live,
+}
+
+/// Reads the os_id stored in a flink
+fn read_flink(flink_path: &Path) -> Result<String, ShmemError> {
+    parse_flink(&read_flink_contents(flink_path)?)
+}
+
+/// Extracts the os_id from the content of a flink
+fn parse_flink(contents: &str) -> Result<String, ShmemError> {
+    // Tolerate links touched by editors or scripts
+    let unique_id = contents
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(|c: char| c.is_whitespace() || c == '\0');
+    if unique_id.is_empty() {
+        return Err(ShmemError::LinkCorrupt);
+    }
+    Ok(unique_id.to_string())
+}
+
+/// Reads the whole content of a flink
+fn read_flink_contents(flink_path: &Path) -> Result<String, ShmemError> {
+    let mut f = match File::open(flink_path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(ShmemError::LinkDoesNotExist(flink_path.to_path_buf()))
+        }
+        Err(e) => return Err(ShmemError::LinkOpenFailed(e)),
+    };
+    let mut flink_uid = String::new();
+    match f.read_to_string(&mut flink_uid) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::InvalidData => return Err(ShmemError::LinkCorrupt),
+        Err(e) => return Err(ShmemError::LinkReadFailed(e)),
+    }
+    Ok(flink_uid)
+}
+
+/// Creates the flink file `path` holding `contents`, failing if it already exists
+fn write_new_flink(
+    path: &Path,
+    contents: &str,
+    ext: &os_impl::ShmemConfExt,
+) -> Result<(), ShmemError> {
+    let mut f = match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(ShmemError::LinkExists),
+        Err(e) => return Err(ShmemError::LinkCreateFailed(e)),
+    };
+    if let Err(e) = os_impl::set_flink_permissions(ext, &f) {
+        let _ = remove_file(path);
+        return Err(ShmemError::LinkCreateFailed(e));
+    }
+    if let Err(e) = f.write_all(contents.as_bytes()) {
+        let _ = remove_file(path);
+        return Err(ShmemError::LinkWriteFailed(e));
+    }
+    Ok(())
+}
+
+/// Returns the path of the temporary file used to publish the flink of the mapping `unique_id`
+fn flink_tmp_path(flink_path: &Path, unique_id: &str) -> PathBuf {
+    let mut file_name = OsString::from(".");
+    file_name.push(flink_path.file_name().unwrap_or_default());
+    file_name.push(format!(".{}.tmp", unique_id.trim_start_matches('/')));
+    flink_path.with_file_name(file_name)
+}
+
 /// Structure used to extract information from an existing shared memory mapping
 pub struct Shmem {
     config: ShmemConf,
     mapping: os_impl::MapData,
+    on_unmap: Vec<Box<dyn FnOnce()>>,
+}
+/// Prints the identifiers and size of the mapping, never its contents
+impl std::fmt::Debug for Shmem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Shmem")
+            .field("os_id", &self.get_os_id())
+            .field("flink_path", &self.get_flink_path())
+            .field("len", &self.len())
+            .field("owner", &self.is_owner())
+            .field("ptr", &self.as_ptr())
+            .finish()
+    }
+}
+impl Drop for Shmem {
+    fn drop(&mut self) {
+        self.run_on_unmap();
+        self.wipe_if_needed();
+    }
 }
 #[allow(clippy::len_without_is_empty)]
 impl Shmem {
@@ -240,6 +735,9 @@ impl Shmem {
     /// Allows for gaining/releasing ownership of the mapping
     ///
     /// Warning : You must ensure at least one process owns the mapping in order to ensure proper cleanup code is ran
+    ///
+    /// After a `fork()`, the child inherits a copy of the owner's `Shmem` and would delete the mapping when
+    /// dropping it. The child should call `set_owner(false)` first thing so only the parent cleans up.
     pub fn set_owner(&mut self, is_owner: bool) -> bool {
         self.mapping.set_owner(is_owner);
 
@@ -251,18 +749,118 @@ impl Shmem {
     pub fn get_os_id(&self) -> &str {
         self.mapping.unique_id.as_str()
     }
+    /// Unmaps the memory and, if we are the owner, deletes the mapping and its file link
+    ///
+    /// Dropping a `Shmem` does the same but can only log failures (with the `logging` feature). Every cleanup
+    /// step is attempted and the first failure is returned.
+    pub fn close(mut self) -> Result<(), ShmemError> {
+        self.run_on_unmap();
+        self.wipe_if_needed();
+        let flink_res = self.config.remove_flink();
+        let map_res = self.mapping.close();
+        flink_res.and(map_res)
+    }
+    /// Maps the same shared memory a second time, like `File::try_clone()`
+    ///
+    /// The new `Shmem` is independent from this one, never owns the mapping and can be dropped at any time.
+    /// It is mapped wherever the OS picks, even for mappings created with [`ShmemConf::fixed_address`].
+    /// The file link is not read again, on unix the file descriptor is duplicated instead.
+    pub fn try_clone(&self) -> Result<Shmem, ShmemError> {
+        self.clone_with_access(self.config.access)
+    }
+    /// Like [`Shmem::try_clone`] but the new mapping has no write access, see [`ShmemConf::read_only`]
+    ///
+    /// This gives a process a read-only view next to its writable one, both share the same memory.
+    pub fn try_clone_read_only(&self) -> Result<Shmem, ShmemError> {
+        self.clone_with_access(MapAccess::ReadOnly)
+    }
+    fn clone_with_access(&self, access: MapAccess) -> Result<Shmem, ShmemError> {
+        let mapping =
+            os_impl::clone_mapping(&self.mapping, access, self.config.window, &self.config.ext)?;
+        let mut config = self.config.clone();
+        config.owner = false;
+        config.access = access;
+        // The clone can't live at the same address
+        config.address = None;
+
+        Ok(Shmem {
+            config,
+            mapping,
+            on_unmap: Vec::new(),
+        })
+    }
+    /// Overwrites the whole mapping with zeros
+    ///
+    /// The writes are volatile so the compiler can't optimize them away, even right before the mapping is
+    /// dropped. Every process mapping the memory sees the zeros, callers must make sure nobody else uses it.
+    ///
+    /// # Panics
+    /// Panics if the mapping is read-only
+    pub fn zeroize(&mut self) {
+        assert!(!self.is_read_only(), "Cannot zeroize a read-only mapping");
+
+        let ptr = self.as_ptr();
+        for i in 0..self.len() {
+            unsafe { ptr.add(i).write_volatile(0) };
+        }
+        compiler_fence(Ordering::SeqCst);
+    }
+    /// Registers `f` to run right before the memory is unmapped, when the `Shmem` is dropped or closed
+    ///
+    /// This ties the lifetime of resources derived from the mapping (pointers registered with a C library,
+    /// buffers to flush, etc...) to the `Shmem`. Callbacks run in the reverse order they were registered in.
+    /// A panicking callback is caught so the remaining callbacks still run and the memory is still unmapped,
+    /// unless the crate is built with `panic = "abort"`. Clones from [`Shmem::try_clone`] don't inherit them.
+    pub fn on_unmap<F: FnOnce() + 'static>(&mut self, f: F) {
+        self.on_unmap.push(Box::new(f));
+    }
+    fn run_on_unmap(&mut self) {
+        while let Some(f) = self.on_unmap.pop() {
+            if std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).is_err() {
+                debug!("on_unmap callback for '{}' panicked", self.get_os_id());
+            }
+        }
+    }
+    /// Zeroes the memory once if the owner asked for it with [`ShmemConf::wipe_on_drop`]
+    fn wipe_if_needed(&mut self) {
+        if self.config.wipe_on_drop && self.is_owner() && !self.is_read_only() {
+            debug!("Wiping shared memory mapping '{}'", self.get_os_id());
+            self.zeroize();
+        }
+        self.config.wipe_on_drop = false;
+    }
+    /// Returns whether the mapping was opened with [`ShmemConf::read_only`]
+    pub fn is_read_only(&self) -> bool {
+        self.config.access == MapAccess::ReadOnly
+    }
+    /// Returns whether the mapping was opened with [`ShmemConf::copy_on_write`]
+    pub fn is_copy_on_write(&self) -> bool {
+        self.config.access == MapAccess::CopyOnWrite
+    }
     /// Returns the flink path if present
     pub fn get_flink_path(&self) -> Option<&PathBuf> {
         self.config.flink_path.as_ref()
     }
     /// Returns the total size of the mapping
+    ///
+    /// This is the size of the OS object and is the same for the creator and the openers. Mappings created by
+    /// this crate are rounded up to the page size, so this can be larger than the `size()` given to `create()`.
     pub fn len(&self) -> usize {
         self.mapping.map_size
     }
     /// Returns a raw pointer to the mapping
+    ///
+    /// The pointer is only valid while this `Shmem` is alive and `len()` bytes can be accessed through it.
+    /// Nothing synchronizes accesses with other processes, callers (including C code the pointer is handed to)
+    /// are responsible for it.
     pub fn as_ptr(&self) -> *mut u8 {
         self.mapping.as_mut_ptr()
     }
+    /// Returns the half-open range of addresses covered by the mapping, see [`Shmem::as_ptr`]
+    pub fn as_ptr_range(&self) -> Range<*mut u8> {
+        let start = self.as_ptr();
+        start..start.wrapping_add(self.len())
+    }
     /// Returns mapping as a byte slice
     /// # Safety
     /// This function is unsafe because it is impossible to ensure the range of bytes is immutable
@@ -272,7 +870,61 @@ impl Shmem {
     /// Returns mapping as a mutable byte slice
     /// # Safety
     /// This function is unsafe because it is impossible to ensure the returned mutable refence is unique/exclusive
+    /// and writing to a read-only mapping crashes the process
     pub unsafe fn as_slice_mut(&mut self) -> &mut [u8] {
         std::slice::from_raw_parts_mut(self.as_ptr(), self.len())
     }
+    /// Locks the pages of the mapping in physical memory so they can not be swapped out
+    ///
+    /// This uses `mlock()` on unix and `VirtualLock()` on Windows. Locked pages count against the process limit
+    /// (`RLIMIT_MEMLOCK` on unix, the minimum working set size on Windows) and `ShmemError::MemoryLockLimit`
+    /// is returned when it would be exceeded.
+    pub fn lock_in_memory(&self) -> Result<(), ShmemError> {
+        self.mapping.lock_in_memory()
+    }
+    /// Unlocks pages previously locked with [`Shmem::lock_in_memory`]
+    pub fn unlock_in_memory(&self) -> Result<(), ShmemError> {
+        self.mapping.unlock_in_memory()
+    }
+    /// Writes back the modified pages of the whole mapping, see [`Shmem::flush_range`]
+    pub fn flush(&self) -> Result<(), ShmemError> {
+        self.flush_range(0, self.len())
+    }
+    /// Writes back the modified pages covering `[offset, offset + len)` to the object backing the mapping
+    ///
+    /// The range is extended to page boundaries internally. This mostly matters on Windows where mappings
+    /// are backed by a file, unix shared memory objects have no storage to write back to.
+    pub fn flush_range(&self, offset: usize, len: usize) -> Result<(), ShmemError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len() => {}
+            _ => return Err(ShmemError::RangeOutOfBounds),
+        }
+        // FlushViewOfFile() flushes up to the end of the view when given a length of 0
+        if len == 0 {
+            return Ok(());
+        }
+        self.mapping.flush(offset, len)
+    }
+    /// Brings every page of the mapping into memory, see [`Shmem::prefault_range`]
+    pub fn prefault(&self) -> Result<(), ShmemError> {
+        self.prefault_range(0, self.len())
+    }
+    /// Brings the pages covering `[offset, offset + len)` into memory so the first accesses don't fault
+    ///
+    /// Pages are otherwise only allocated/read in on first access, which causes latency spikes. Windows uses
+    /// `PrefetchVirtualMemory()`, other platforms read one byte of every page.
+    pub fn prefault_range(&self, offset: usize, len: usize) -> Result<(), ShmemError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len() => {}
+            _ => return Err(ShmemError::RangeOutOfBounds),
+        }
+        self.mapping.prefault(offset, len)
+    }
+}
+
+/// Faults in `[ptr, ptr + len)` by reading one byte every `page_size` bytes
+fn touch_pages(ptr: *const u8, len: usize, page_size: usize) {
+    for i in (0..len).step_by(page_size) {
+        unsafe { ptr.add(i).read_volatile() };
+    }
 }
@@ -1,17 +1,152 @@
+use std::fs::{File, Permissions};
 use std::num::NonZeroUsize;
-use std::os::unix::io::RawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, IntoRawFd, OwnedFd, RawFd};
 use std::ptr::null_mut;
 
 use crate::log::*;
 use nix::fcntl::OFlag;
-use nix::sys::mman::{mmap, munmap, shm_open, shm_unlink, MapFlags, ProtFlags};
-use nix::sys::stat::{fstat, Mode};
-use nix::unistd::{close, ftruncate};
+use nix::sys::mman::{
+    mlock, mmap, msync, munlock, munmap, shm_open, shm_unlink, MapFlags, MsFlags, ProtFlags,
+};
+use nix::sys::stat::{fchmod, fstat, Mode};
+use nix::unistd::{close, dup, ftruncate};
 
-use crate::ShmemError;
+use crate::{MapAccess, Shmem, ShmemConf, ShmemError};
 
-#[derive(Clone, Default)]
-pub struct ShmemConfExt;
+#[derive(Clone, Debug, Default)]
+pub struct ShmemConfExt {
+    anonymous: bool,
+    permissions: Option<u32>,
+    #[cfg(target_os = "linux")]
+    dont_dump: bool,
+    #[cfg(target_os = "linux")]
+    dont_fork: bool,
+    #[cfg(target_os = "linux")]
+    huge_pages: bool,
+    #[cfg(target_os = "linux")]
+    numa_node: Option<u32>,
+}
+
+impl ShmemConf {
+    /// If set to true, `create()` unlinks the shared memory object as soon as it is mapped
+    ///
+    /// The mapping then has no name other processes could open and is freed once every process closed it.
+    /// It can only be shared by handing its file descriptor (see `Shmem::as_raw_fd()`) to a child process or
+    /// through a unix socket, the receiving end uses `open_fd()`. Anonymous mappings can't have a file link,
+    /// `create()` fails with `ShmemError::AnonymousFlink` when one is set.
+    pub fn anonymous(mut self, anonymous: bool) -> Self {
+        self.ext.anonymous = anonymous;
+        self
+    }
+
+    /// Sets the permission bits of the shared memory object and its file link, e.g. `0o640`
+    ///
+    /// These are applied exactly, regardless of the process umask. By default, the shared memory object is only
+    /// accessible by the current user (`0o600`) and the file link gets the usual file permissions.
+    pub fn permissions(mut self, mode: u32) -> Self {
+        self.ext.permissions = Some(mode);
+        self
+    }
+
+    /// Opens the shared memory object behind a file descriptor received from another process
+    ///
+    /// The descriptor typically comes from `Shmem::as_raw_fd()` in the sender, sent through a unix socket with
+    /// `SCM_RIGHTS`. The `Shmem` takes ownership of `fd` and closes it when dropped. The whole object is mapped
+    /// unless a `window()` is set. Such mappings have an empty os_id.
+    pub fn open_fd(mut self, fd: OwnedFd) -> Result<Shmem, ShmemError> {
+        let mapping = map_existing(
+            fd.into_raw_fd(),
+            "",
+            self.access,
+            self.address,
+            self.window,
+            &self.ext,
+        )?;
+        if mapping.map_size < self.min_size {
+            return Err(ShmemError::SizeTooSmall(mapping.map_size));
+        }
+        self.size = mapping.map_size;
+        self.owner = false;
+
+        Ok(Shmem {
+            config: self,
+            mapping,
+            on_unmap: Vec::new(),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ShmemConf {
+    /// If set to true, excludes the mapping from core dumps of this process (`MADV_DONTDUMP`)
+    ///
+    /// This is useful for mappings that hold secrets or that are too large to be worth dumping
+    pub fn dont_dump(mut self, dont_dump: bool) -> Self {
+        self.ext.dont_dump = dont_dump;
+        self
+    }
+
+    /// If set to true, child processes created with `fork()` do not inherit the mapping (`MADV_DONTFORK`)
+    pub fn dont_fork(mut self, dont_fork: bool) -> Self {
+        self.ext.dont_fork = dont_fork;
+        self
+    }
+
+    /// If set to true, asks the kernel to back the mapping with transparent huge pages (`MADV_HUGEPAGE`)
+    ///
+    /// This reduces TLB pressure for large mappings. It is only a hint : huge pages are used when
+    /// `/sys/kernel/mm/transparent_hugepage/shmem_enabled` is set to `advise` (or `always`) and only for the
+    /// 2MB aligned parts of the mapping, kernels without transparent huge page support ignore it. Explicit
+    /// `MAP_HUGETLB` pages are not available for POSIX shared memory.
+    pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+        self.ext.huge_pages = huge_pages;
+        self
+    }
+
+    /// Binds the pages of the mapping to the NUMA node `node` (`mbind(MPOL_BIND)`) and faults them in
+    ///
+    /// The policy is attached to the shared memory object, so pages are allocated on `node` no matter which
+    /// process touches them first. Pages already allocated elsewhere are only moved if this process is their
+    /// sole user. See [`Shmem::numa_node_of`] to check where pages ended up. `create()` and `open()` fail with
+    /// `ShmemError::NumaUnsupported` when the kernel lacks NUMA support or the syscall is not permitted, and
+    /// with `ShmemError::NumaNodeInvalid` for nodes past the 1024 the kernel can address.
+    pub fn numa_node(mut self, node: u32) -> Self {
+        self.ext.numa_node = Some(node);
+        self
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Shmem {
+    /// Returns the NUMA node the page at `offset` resides on, faulting it in if needed
+    pub fn numa_node_of(&self, offset: usize) -> Result<u32, ShmemError> {
+        if offset >= self.len() {
+            return Err(ShmemError::RangeOutOfBounds);
+        }
+        let addr = unsafe { self.mapping.map_ptr.add(offset) };
+
+        let mut node: libc::c_int = 0;
+        trace!(
+            "get_mempolicy(NULL, 0, {:p}, MPOL_F_NODE | MPOL_F_ADDR)",
+            addr
+        );
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_get_mempolicy,
+                &mut node as *mut libc::c_int,
+                std::ptr::null_mut::<libc::c_ulong>(),
+                0 as libc::c_ulong,
+                addr,
+                MPOL_F_NODE | MPOL_F_ADDR,
+            )
+        };
+        if res != 0 {
+            return Err(numa_error());
+        }
+        Ok(node as u32)
+    }
+}
 
 pub struct MapData {
     //On linux, you must shm_unlink() the object created for the mapping. It wont disappear automatically.
@@ -32,12 +167,133 @@ impl MapData {
     pub fn as_mut_ptr(&self) -> *mut u8 {
         self.map_ptr
     }
+
+    pub fn flush(&self, offset: usize, len: usize) -> Result<(), ShmemError> {
+        // msync() wants a page aligned address
+        let start = offset - (offset % page_size());
+        let len = len + (offset - start);
+        let addr = unsafe { self.map_ptr.add(start) };
+
+        trace!("msync({:p}, {}, MS_SYNC)", addr, len);
+        match unsafe { msync(addr as *mut _, len, MsFlags::MS_SYNC) } {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ShmemError::MapFlushFailed(e as u32)),
+        }
+    }
+
+    pub fn prefault(&self, offset: usize, len: usize) -> Result<(), ShmemError> {
+        // Start at the page boundary so every page of the range gets touched
+        let start = offset - (offset % page_size());
+        let len = len + (offset - start);
+        let addr = unsafe { self.map_ptr.add(start) };
+
+        trace!("touch_pages({:p}, {})", addr, len);
+        crate::touch_pages(addr, len, page_size());
+        Ok(())
+    }
+
+    pub fn lock_in_memory(&self) -> Result<(), ShmemError> {
+        trace!("mlock({:p}, {})", self.map_ptr, self.map_size);
+        match unsafe { mlock(self.map_ptr as *const _, self.map_size) } {
+            Ok(_) => Ok(()),
+            Err(nix::Error::ENOMEM | nix::Error::EPERM) => Err(ShmemError::MemoryLockLimit),
+            Err(e) => Err(ShmemError::UnknownOsError(e as u32)),
+        }
+    }
+
+    pub fn unlock_in_memory(&self) -> Result<(), ShmemError> {
+        trace!("munlock({:p}, {})", self.map_ptr, self.map_size);
+        match unsafe { munlock(self.map_ptr as *const _, self.map_size) } {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ShmemError::UnknownOsError(e as u32)),
+        }
+    }
+
+    pub fn resident_bytes(&self) -> Result<usize, ShmemError> {
+        // One status byte per page, the lowest bit tells whether the page is resident
+        let page_size = page_size();
+        let mut pages = vec![0u8; self.map_size.div_ceil(page_size)];
+
+        trace!("mincore({:p}, {})", self.map_ptr, self.map_size);
+        let res = unsafe {
+            libc::mincore(
+                self.map_ptr as *mut _,
+                self.map_size,
+                pages.as_mut_ptr() as *mut _,
+            )
+        };
+        if res != 0 {
+            return Err(ShmemError::UnknownOsError(nix::errno::Errno::last() as u32));
+        }
+        Ok(pages.iter().filter(|p| *p & 1 != 0).count() * page_size)
+    }
+}
+
+impl Shmem {
+    /// Returns how many bytes of the mapping are currently resident in RAM, using `mincore()`
+    ///
+    /// Pages count as soon as any process touched them. This tells apart the size of a large, mostly
+    /// untouched mapping from the memory it actually uses.
+    pub fn resident_bytes(&self) -> Result<usize, ShmemError> {
+        self.mapping.resident_bytes()
+    }
+
+    /// Describes the whole mapping as an `iovec`, e.g. to register it with `IORING_REGISTER_BUFFERS`
+    ///
+    /// The mapping never moves nor changes size, the `iovec` stays valid until the `Shmem` is dropped.
+    /// Buffers registered with the kernel must be unregistered before that.
+    pub fn as_iovec(&self) -> libc::iovec {
+        libc::iovec {
+            iov_base: self.as_ptr() as *mut _,
+            iov_len: self.len(),
+        }
+    }
+}
+
+impl AsRawFd for Shmem {
+    /// Returns the file descriptor of the shared memory object
+    ///
+    /// The descriptor stays owned by the `Shmem` and is closed when it is dropped. Sending it with `SCM_RIGHTS`
+    /// installs a new descriptor in the receiving process, so the `Shmem` only has to outlive the `sendmsg()`
+    /// call. Duplicate it (`dup()`) to keep it past the lifetime of the `Shmem` in this process.
+    fn as_raw_fd(&self) -> RawFd {
+        self.mapping.map_fd
+    }
+}
+
+/// Applies the requested permissions to a newly created file link
+pub fn set_flink_permissions(ext: &ShmemConfExt, flink: &File) -> std::io::Result<()> {
+    if let Some(mode) = ext.permissions {
+        trace!("fchmod(flink, {:o})", mode);
+        flink.set_permissions(Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Returns the size of a memory page
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
 }
 
 /// Shared memory teardown for linux
 impl Drop for MapData {
     ///Takes care of properly closing the SharedMem (munmap(), shmem_unlink(), close())
     fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl MapData {
+    pub fn set_owner(&mut self, is_owner: bool) -> bool {
+        let prev_val = self.owner;
+        self.owner = is_owner;
+        prev_val
+    }
+
+    /// Runs every teardown step once and returns the first failure
+    pub fn close(&mut self) -> Result<(), ShmemError> {
+        let mut res = Ok(());
+
         //Unmap memory
         if !self.map_ptr.is_null() {
             trace!(
@@ -45,9 +301,11 @@ impl Drop for MapData {
                 self.map_ptr,
                 self.map_size
             );
-            if let Err(_e) = unsafe { munmap(self.map_ptr as *mut _, self.map_size) } {
-                debug!("Failed to munmap() shared memory mapping : {}", _e);
+            if let Err(e) = unsafe { munmap(self.map_ptr as *mut _, self.map_size) } {
+                debug!("Failed to munmap() shared memory mapping : {}", e);
+                res = res.and(Err(ShmemError::MapCloseFailed(e as u32)));
             };
+            self.map_ptr = null_mut();
         }
 
         //Unlink shmem
@@ -56,53 +314,183 @@ impl Drop for MapData {
             if self.owner {
                 debug!("Deleting persistent mapping");
                 trace!("shm_unlink({})", self.unique_id.as_str());
-                if let Err(_e) = shm_unlink(self.unique_id.as_str()) {
-                    debug!("Failed to shm_unlink() shared memory : {}", _e);
+                if let Err(e) = shm_unlink(self.unique_id.as_str()) {
+                    debug!("Failed to shm_unlink() shared memory : {}", e);
+                    res = res.and(Err(ShmemError::MapCloseFailed(e as u32)));
                 };
+                self.owner = false;
             }
 
             trace!("close({})", self.map_fd);
-            if let Err(_e) = close(self.map_fd) {
+            if let Err(e) = close(self.map_fd) {
                 debug!(
                     "os_impl::Linux : Failed to close() shared memory file descriptor : {}",
-                    _e
+                    e
                 );
+                res = res.and(Err(ShmemError::MapCloseFailed(e as u32)));
             };
+            self.map_fd = 0;
         }
+
+        res
     }
 }
 
-impl MapData {
-    pub fn set_owner(&mut self, is_owner: bool) -> bool {
-        let prev_val = self.owner;
-        self.owner = is_owner;
-        prev_val
+/// Applies the madvise() hints requested in the config to a freshly mapped region
+#[cfg(target_os = "linux")]
+fn advise(map: &MapData, ext: &ShmemConfExt) -> Result<(), ShmemError> {
+    use nix::sys::mman::{madvise, MmapAdvise};
+
+    let mut advices = Vec::new();
+    if ext.dont_dump {
+        advices.push(MmapAdvise::MADV_DONTDUMP);
+    }
+    if ext.dont_fork {
+        advices.push(MmapAdvise::MADV_DONTFORK);
+    }
+    if ext.huge_pages {
+        advices.push(MmapAdvise::MADV_HUGEPAGE);
+    }
+
+    for advice in advices {
+        trace!("madvise({:p}, {}, {:?})", map.map_ptr, map.map_size, advice);
+        match unsafe { madvise(map.map_ptr as *mut _, map.map_size, advice) } {
+            Ok(_) => {}
+            // Kernels built without transparent huge pages reject the hint
+            Err(nix::Error::EINVAL) if advice == MmapAdvise::MADV_HUGEPAGE => {
+                debug!("Transparent huge pages are not supported, ignoring huge_pages()");
+            }
+            Err(e) => return Err(ShmemError::UnknownOsError(e as u32)),
+        }
+    }
+
+    if let Some(node) = ext.numa_node {
+        bind_numa_node(map, node)?;
+        // Allocate every page now so they all follow the policy
+        map.prefault(0, map.map_size)?;
+    }
+    Ok(())
+}
+
+// Flags from linux/mempolicy.h that libc doesn't define
+#[cfg(target_os = "linux")]
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+#[cfg(target_os = "linux")]
+const MPOL_F_NODE: libc::c_ulong = 1 << 0;
+#[cfg(target_os = "linux")]
+const MPOL_F_ADDR: libc::c_ulong = 1 << 1;
+/// Largest MAX_NUMNODES a kernel can be configured with (NODES_SHIFT of 10)
+#[cfg(target_os = "linux")]
+const MAX_NUMA_NODES: u32 = 1024;
+
+/// Sets the MPOL_BIND policy of the mapping to a single NUMA node
+#[cfg(target_os = "linux")]
+fn bind_numa_node(map: &MapData, node: u32) -> Result<(), ShmemError> {
+    // The mask is sized from the node, don't allocate one the kernel would reject anyway
+    if node >= MAX_NUMA_NODES {
+        return Err(ShmemError::NumaNodeInvalid(node));
+    }
+    let bits = libc::c_ulong::BITS as usize;
+    let mut node_mask = vec![0 as libc::c_ulong; node as usize / bits + 1];
+    node_mask[node as usize / bits] |= 1 << (node as usize % bits);
+
+    trace!(
+        "mbind({:p}, {}, MPOL_BIND, [{}], MPOL_MF_MOVE)",
+        map.map_ptr,
+        map.map_size,
+        node
+    );
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            map.map_ptr,
+            map.map_size as libc::c_ulong,
+            libc::MPOL_BIND,
+            node_mask.as_ptr(),
+            // The kernel ignores the last bit of the mask
+            (node_mask.len() * bits + 1) as libc::c_ulong,
+            MPOL_MF_MOVE,
+        )
+    };
+    if res != 0 {
+        return Err(numa_error());
+    }
+    Ok(())
+}
+
+/// Converts the errno of a failed NUMA syscall
+#[cfg(target_os = "linux")]
+fn numa_error() -> ShmemError {
+    match nix::errno::Errno::last() {
+        // Kernels without CONFIG_NUMA and seccomp profiles like docker's default one
+        nix::Error::ENOSYS | nix::Error::EPERM => ShmemError::NumaUnsupported,
+        e => ShmemError::UnknownOsError(e as u32),
+    }
+}
+#[cfg(not(target_os = "linux"))]
+fn advise(_map: &MapData, _ext: &ShmemConfExt) -> Result<(), ShmemError> {
+    Ok(())
+}
+
+/// Returns the address hint and flags to pass to mmap() for the requested fixed address
+fn fixed_address_args(addr: Option<usize>) -> (Option<NonZeroUsize>, MapFlags) {
+    match addr.and_then(NonZeroUsize::new) {
+        // Fails with EEXIST instead of replacing existing mappings like MAP_FIXED
+        #[cfg(target_os = "linux")]
+        Some(addr) => (Some(addr), MapFlags::MAP_FIXED_NOREPLACE),
+        #[cfg(not(target_os = "linux"))]
+        Some(addr) => (Some(addr), MapFlags::empty()),
+        None => (None, MapFlags::empty()),
+    }
+}
+
+/// Makes sure the mapping ended up at the requested address. Without MAP_FIXED_NOREPLACE (or on kernels
+/// older than 4.17), the address is only a hint and the kernel maps elsewhere when it is in use.
+fn check_fixed_address(map: &MapData, addr: Option<usize>) -> Result<(), ShmemError> {
+    match addr {
+        Some(addr) if map.map_ptr as usize != addr => Err(ShmemError::AddressUnavailable),
+        _ => Ok(()),
     }
 }
 
 /// Creates a mapping specified by the uid and size
-pub fn create_mapping(unique_id: &str, map_size: usize) -> Result<MapData, ShmemError> {
+pub fn create_mapping(
+    unique_id: &str,
+    map_size: usize,
+    addr: Option<usize>,
+    ext: &ShmemConfExt,
+) -> Result<MapData, ShmemError> {
     //Create shared memory file descriptor
     debug!("Creating persistent mapping at {}", unique_id);
 
+    //Round the size up to whole pages, openers get their size from the object so this is the real size
+    let map_size = map_size
+        .checked_next_multiple_of(page_size())
+        .ok_or(ShmemError::SizeOverflow(map_size))?;
     let nz_map_size = NonZeroUsize::new(map_size).ok_or(ShmemError::MapSizeZero)?;
 
+    let mode = match ext.permissions {
+        Some(mode) => Mode::from_bits_truncate(mode as _),
+        None => Mode::S_IRUSR | Mode::S_IWUSR, //Permission allow user+rw
+    };
     let shmem_fd = match shm_open(
         unique_id, //Unique name that usualy pops up in /dev/shm/
         OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_RDWR, //create exclusively (error if collision) and read/write to allow resize
-        Mode::S_IRUSR | Mode::S_IWUSR,                  //Permission allow user+rw
+        mode,
     ) {
         Ok(v) => {
             trace!(
                 "shm_open({}, {:X}, {:X}) == {}",
                 unique_id,
                 OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_RDWR,
-                Mode::S_IRUSR | Mode::S_IWUSR,
+                mode,
                 v
             );
             v
         }
         Err(nix::Error::EEXIST) => return Err(ShmemError::MappingIdExists),
+        Err(nix::Error::ENAMETOOLONG | nix::Error::EINVAL) => return Err(ShmemError::InvalidOsId),
+        Err(nix::Error::EACCES | nix::Error::EPERM) => return Err(ShmemError::MapPermissionDenied),
         Err(e) => return Err(ShmemError::MapCreateFailed(e as u32)),
     };
 
@@ -114,39 +502,67 @@ pub fn create_mapping(unique_id: &str, map_size: usize) -> Result<MapData, Shmem
         map_ptr: null_mut(),
     };
 
+    //shm_open() applies the umask to the mode, set explicit permissions again
+    if ext.permissions.is_some() {
+        trace!("fchmod({}, {:X})", new_map.map_fd, mode);
+        if let Err(e) = fchmod(new_map.map_fd, mode) {
+            return Err(ShmemError::MapCreateFailed(e as u32));
+        }
+    }
+
     //Enlarge the memory descriptor file size to the requested map size
     debug!("Creating memory mapping");
     trace!("ftruncate({}, {})", new_map.map_fd, new_map.map_size);
     match ftruncate(new_map.map_fd, new_map.map_size as _) {
         Ok(_) => {}
+        Err(nix::Error::ENOSPC | nix::Error::ENOMEM | nix::Error::EFBIG) => {
+            return Err(ShmemError::OutOfMemory(new_map.map_size))
+        }
         Err(e) => return Err(ShmemError::UnknownOsError(e as u32)),
     };
 
     //Put the mapping in our address space
     debug!("Loading mapping into address space");
+    let (addr_hint, fixed_flags) = fixed_address_args(addr);
     new_map.map_ptr = match unsafe {
         mmap(
-            None,                                         //Desired addr
+            addr_hint,                                    //Desired addr
             nz_map_size,                                  //size of mapping
             ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, //Permissions on pages
-            MapFlags::MAP_SHARED,                         //What kind of mapping
+            MapFlags::MAP_SHARED | fixed_flags,           //What kind of mapping
             new_map.map_fd,                               //fd
             0,                                            //Offset into fd
         )
     } {
         Ok(v) => {
             trace!(
-                "mmap(NULL, {}, {:X}, {:X}, {}, 0) == {:p}",
+                "mmap({:?}, {}, {:X}, {:X}, {}, 0) == {:p}",
+                addr_hint,
                 new_map.map_size,
                 ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_SHARED,
+                MapFlags::MAP_SHARED | fixed_flags,
                 new_map.map_fd,
                 v
             );
             v as *mut _
         }
+        Err(nix::Error::EEXIST) => return Err(ShmemError::AddressUnavailable),
+        Err(nix::Error::ENOMEM) => return Err(ShmemError::OutOfMemory(new_map.map_size)),
         Err(e) => return Err(ShmemError::MapCreateFailed(e as u32)),
     };
+    check_fixed_address(&new_map, addr)?;
+
+    advise(&new_map, ext)?;
+
+    if ext.anonymous {
+        debug!("Unlinking anonymous mapping");
+        trace!("shm_unlink({})", unique_id);
+        if let Err(e) = shm_unlink(unique_id) {
+            return Err(ShmemError::MapCreateFailed(e as u32));
+        }
+        // Nothing left to delete when dropped
+        new_map.owner = false;
+    }
 
     Ok(new_map)
 }
@@ -155,28 +571,85 @@ pub fn create_mapping(unique_id: &str, map_size: usize) -> Result<MapData, Shmem
 pub fn open_mapping(
     unique_id: &str,
     _map_size: usize,
-    _ext: &ShmemConfExt,
+    access: MapAccess,
+    addr: Option<usize>,
+    window: Option<(usize, usize)>,
+    ext: &ShmemConfExt,
 ) -> Result<MapData, ShmemError> {
     //Open shared memory
     debug!("Openning persistent mapping at {}", unique_id);
-    let shmem_fd = match shm_open(
-        unique_id,
-        OFlag::O_RDWR, //Open read write
-        Mode::S_IRUSR,
-    ) {
+    let oflag = if access == MapAccess::ReadWrite {
+        OFlag::O_RDWR
+    } else {
+        OFlag::O_RDONLY
+    };
+    let shmem_fd = match shm_open(unique_id, oflag, Mode::S_IRUSR) {
         Ok(v) => {
             trace!(
                 "shm_open({}, {:X}, {:X}) == {}",
                 unique_id,
-                OFlag::O_RDWR,
+                oflag,
                 Mode::S_IRUSR,
                 v
             );
             v
         }
+        Err(nix::Error::ENAMETOOLONG | nix::Error::EINVAL) => return Err(ShmemError::InvalidOsId),
+        Err(nix::Error::EACCES | nix::Error::EPERM) => return Err(ShmemError::MapPermissionDenied),
+        Err(e) => return Err(ShmemError::MapOpenFailed(e as u32)),
+    };
+
+    map_existing(shmem_fd, unique_id, access, addr, window, ext)
+}
+
+/// Returns whether the mapping is unlinked right after its creation
+pub fn is_anonymous(ext: &ShmemConfExt) -> bool {
+    ext.anonymous
+}
+
+/// Returns whether a shared memory object named `unique_id` exists
+pub fn mapping_exists(unique_id: &str, _ext: &ShmemConfExt) -> Result<bool, ShmemError> {
+    trace!("shm_open({}, {:X}, 0)", unique_id, OFlag::O_RDONLY);
+    match shm_open(unique_id, OFlag::O_RDONLY, Mode::empty()) {
+        Ok(fd) => {
+            let _ = close(fd);
+            Ok(true)
+        }
+        Err(nix::Error::ENOENT) => Ok(false),
+        // Someone else's object
+        Err(nix::Error::EACCES | nix::Error::EPERM) => Ok(true),
+        Err(nix::Error::ENAMETOOLONG | nix::Error::EINVAL) => Err(ShmemError::InvalidOsId),
+        Err(e) => Err(ShmemError::UnknownOsError(e as u32)),
+    }
+}
+
+/// Maps the object behind an existing mapping a second time, through a duplicate of its file descriptor
+pub fn clone_mapping(
+    map: &MapData,
+    access: MapAccess,
+    window: Option<(usize, usize)>,
+    ext: &ShmemConfExt,
+) -> Result<MapData, ShmemError> {
+    let shmem_fd = match dup(map.map_fd) {
+        Ok(v) => {
+            trace!("dup({}) == {}", map.map_fd, v);
+            v
+        }
         Err(e) => return Err(ShmemError::MapOpenFailed(e as u32)),
     };
 
+    map_existing(shmem_fd, &map.unique_id, access, None, window, ext)
+}
+
+/// Maps the whole shared memory object behind an open file descriptor
+fn map_existing(
+    shmem_fd: RawFd,
+    unique_id: &str,
+    access: MapAccess,
+    addr: Option<usize>,
+    window: Option<(usize, usize)>,
+    ext: &ShmemConfExt,
+) -> Result<MapData, ShmemError> {
     let mut new_map: MapData = MapData {
         owner: false,
         unique_id: String::from(unique_id),
@@ -191,33 +664,67 @@ pub fn open_mapping(
         Err(e) => return Err(ShmemError::MapOpenFailed(e as u32)),
     };
 
+    //Only map the requested window of the object
+    let mut offset = 0;
+    if let Some((win_offset, win_len)) = window {
+        if win_offset % page_size() != 0 {
+            return Err(ShmemError::UnalignedOffset(win_offset));
+        }
+        match win_offset.checked_add(win_len) {
+            Some(end) if end <= new_map.map_size => {}
+            _ => return Err(ShmemError::RangeOutOfBounds),
+        }
+        offset = win_offset;
+        new_map.map_size = win_len;
+    }
+
     let nz_map_size = NonZeroUsize::new(new_map.map_size).ok_or(ShmemError::MapSizeZero)?;
 
+    let prot = if access == MapAccess::ReadOnly {
+        ProtFlags::PROT_READ
+    } else {
+        ProtFlags::PROT_READ | ProtFlags::PROT_WRITE
+    };
+    //Private mappings copy pages on write instead of writing to the object
+    let flags = if access == MapAccess::CopyOnWrite {
+        MapFlags::MAP_PRIVATE
+    } else {
+        MapFlags::MAP_SHARED
+    };
+
     //Map memory into our address space
     debug!("Loading mapping into address space");
+    let (addr_hint, fixed_flags) = fixed_address_args(addr);
     new_map.map_ptr = match unsafe {
         mmap(
-            None,                                         //Desired addr
-            nz_map_size,                                  //size of mapping
-            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, //Permissions on pages
-            MapFlags::MAP_SHARED,                         //What kind of mapping
-            new_map.map_fd,                               //fd
-            0,                                            //Offset into fd
+            addr_hint,           //Desired addr
+            nz_map_size,         //size of mapping
+            prot,                //Permissions on pages
+            flags | fixed_flags, //What kind of mapping
+            new_map.map_fd,      //fd
+            offset as _,         //Offset into fd
         )
     } {
         Ok(v) => {
             trace!(
-                "mmap(NULL, {}, {:X}, {:X}, {}, 0) == {:p}",
+                "mmap({:?}, {}, {:X}, {:X}, {}, {}) == {:p}",
+                addr_hint,
                 new_map.map_size,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_SHARED,
+                prot,
+                flags | fixed_flags,
                 new_map.map_fd,
+                offset,
                 v
             );
             v as *mut _
         }
+        Err(nix::Error::EEXIST) => return Err(ShmemError::AddressUnavailable),
+        Err(nix::Error::ENOMEM) => return Err(ShmemError::OutOfMemory(new_map.map_size)),
         Err(e) => return Err(ShmemError::MapOpenFailed(e as u32)),
     };
+    check_fixed_address(&new_map, addr)?;
+
+    advise(&new_map, ext)?;
 
     Ok(new_map)
 }
@@ -1,16 +1,21 @@
+use std::ffi::c_void;
 use std::fs::{File, OpenOptions};
 use std::io::ErrorKind;
 use std::os::windows::{fs::OpenOptionsExt, io::AsRawHandle};
 use std::path::PathBuf;
 
-use crate::{log::*, ShmemConf};
+use crate::{log::*, MapAccess, ShmemConf};
 use win_sys::*;
 
 use crate::ShmemError;
 
-#[derive(Clone, Default)]
+// Views of a mapping must start at a multiple of this, it is 64KB on every Windows architecture
+const ALLOCATION_GRANULARITY: usize = 64 * 1024;
+
+#[derive(Clone, Debug, Default)]
 pub struct ShmemConfExt {
     allow_raw: bool,
+    global: bool,
 }
 
 impl ShmemConf {
@@ -19,6 +24,39 @@ impl ShmemConf {
         self.ext.allow_raw = allow;
         self
     }
+
+    /// If set to true, the mapping is created/opened in the `Global\` namespace instead of the session one
+    ///
+    /// This allows sharing memory between sessions, e.g. between a service and a desktop application. Creating
+    /// a global mapping from outside session 0 requires the `SeCreateGlobalPrivilege` privilege, otherwise
+    /// `ShmemError::MapPermissionDenied` is returned. As the processes may not share the same temporary
+    /// directory, openers fall back to opening the mapping by name when its backing file can't be found.
+    pub fn global(mut self, global: bool) -> Self {
+        self.ext.global = global;
+        self
+    }
+}
+
+/// A view of a file mapping, unmapped when dropped
+///
+/// Unlike win_sys' `ViewOfFile`, this can be created by `MapViewOfFileEx()` at a specific address
+pub struct MapView(*mut c_void);
+impl MapView {
+    pub fn as_mut_ptr(&self) -> *mut c_void {
+        self.0
+    }
+}
+impl std::fmt::Pointer for MapView {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Pointer::fmt(&self.0, f)
+    }
+}
+impl Drop for MapView {
+    fn drop(&mut self) {
+        unsafe {
+            Memory::UnmapViewOfFile(self.0);
+        }
+    }
 }
 
 pub struct MapData {
@@ -26,7 +64,7 @@ pub struct MapData {
 
     /// Pointer to the first byte of our mapping
     /// Keep this above `file_map` so it gets dropped first
-    pub view: ViewOfFile,
+    pub view: MapView,
 
     /// The handle to our open mapping
     #[allow(dead_code)]
@@ -48,6 +86,14 @@ pub struct MapData {
 impl Drop for MapData {
     ///Takes care of properly closing the SharedMem
     fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+impl MapData {
+    /// Runs the owner's deletion steps once and returns the first failure. The view and mapping handles
+    /// are released when dropped.
+    pub fn close(&mut self) -> Result<(), ShmemError> {
         // Inspired by the boost implementation at
         // https://github.com/boostorg/interprocess/blob/140b50efb3281fa3898f3a4cf939cfbda174718f/include/boost/interprocess/detail/win32_api.hpp
         // Emulate POSIX behavior by
@@ -58,51 +104,59 @@ impl Drop for MapData {
         // deleted once all handles have been closed and no new handles can be opened
         // because the file has been renamed. This matches the behavior of shm_unlink()
         // on unix.
-        if self.owner {
-            let mut base_path = get_tmp_dir().unwrap();
-
-            // 1. Set file attributes so that it deletes itself once everyone has closed it
-            let file_path = base_path.join(self.unique_id.trim_start_matches('/'));
-            debug!("Setting mapping to delete after everyone has closed it");
-            match OpenOptions::new()
-                .access_mode(GENERIC_READ | GENERIC_WRITE | DELETE)
-                .share_mode((FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE).0)
-                .create(false)
-                .attributes((FILE_ATTRIBUTE_TEMPORARY | FILE_FLAG_DELETE_ON_CLOSE).0)
-                .open(&file_path)
-            {
-                Ok(_) => {
-                    // 2. Rename file to prevent further use
-                    base_path.push(&format!(
-                        "{}_deleted",
-                        self.unique_id.trim_start_matches('/')
-                    ));
-                    debug!(
-                        "Renaming {} to {}",
-                        file_path.to_string_lossy(),
-                        base_path.to_string_lossy()
-                    );
-                    if let Err(_e) = std::fs::rename(&file_path, &base_path) {
-                        debug!(
-                            "Failed to rename persistent_file {} : {}",
-                            file_path.to_string_lossy(),
-                            _e
-                        );
-                    }
-                }
-                Err(_e) => {
+        if !self.owner {
+            return Ok(());
+        }
+        self.owner = false;
+
+        let mut base_path = get_tmp_dir()?;
+
+        // 1. Set file attributes so that it deletes itself once everyone has closed it
+        let file_path = base_path.join(self.unique_id.trim_start_matches('/'));
+        debug!("Setting mapping to delete after everyone has closed it");
+        match OpenOptions::new()
+            .access_mode(GENERIC_READ | GENERIC_WRITE | DELETE)
+            .share_mode((FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE).0)
+            .create(false)
+            .attributes((FILE_ATTRIBUTE_TEMPORARY | FILE_FLAG_DELETE_ON_CLOSE).0)
+            .open(&file_path)
+        {
+            Ok(_) => {
+                // 2. Rename file to prevent further use
+                base_path.push(format!(
+                    "{}_deleted",
+                    self.unique_id.trim_start_matches('/')
+                ));
+                debug!(
+                    "Renaming {} to {}",
+                    file_path.to_string_lossy(),
+                    base_path.to_string_lossy()
+                );
+                if let Err(e) = std::fs::rename(&file_path, &base_path) {
                     debug!(
-                        "Failed to set DELETE_ON_CLOSE on persistent_file {} : {}",
+                        "Failed to rename persistent_file {} : {}",
                         file_path.to_string_lossy(),
-                        _e
+                        e
                     );
+                    return Err(ShmemError::MapCloseFailed(
+                        e.raw_os_error().unwrap_or_default() as _,
+                    ));
                 }
-            };
-        }
+            }
+            Err(e) => {
+                debug!(
+                    "Failed to set DELETE_ON_CLOSE on persistent_file {} : {}",
+                    file_path.to_string_lossy(),
+                    e
+                );
+                return Err(ShmemError::MapCloseFailed(
+                    e.raw_os_error().unwrap_or_default() as _,
+                ));
+            }
+        };
+        Ok(())
     }
-}
 
-impl MapData {
     pub fn set_owner(&mut self, is_owner: bool) -> bool {
         let prev_val = self.owner;
         self.owner = is_owner;
@@ -111,6 +165,60 @@ impl MapData {
     pub fn as_mut_ptr(&self) -> *mut u8 {
         self.view.as_mut_ptr() as _
     }
+    pub fn flush(&self, offset: usize, len: usize) -> Result<(), ShmemError> {
+        // FlushViewOfFile() rounds the address down to a page boundary itself
+        let addr = unsafe { self.as_mut_ptr().add(offset) };
+        trace!("FlushViewOfFile({:p}, {})", addr, len);
+        if unsafe { Memory::FlushViewOfFile(addr as _, len) }.as_bool() {
+            Ok(())
+        } else {
+            Err(ShmemError::MapFlushFailed(
+                Error::from_win32().win32_error().unwrap().0,
+            ))
+        }
+    }
+    pub fn prefault(&self, offset: usize, len: usize) -> Result<(), ShmemError> {
+        let addr = unsafe { self.as_mut_ptr().add(offset) };
+        let range = Memory::WIN32_MEMORY_RANGE_ENTRY {
+            VirtualAddress: addr as _,
+            NumberOfBytes: len,
+        };
+        trace!("PrefetchVirtualMemory({:p}, {})", addr, len);
+        // HANDLE(-1) is the GetCurrentProcess() pseudo handle
+        if !unsafe { Memory::PrefetchVirtualMemory(HANDLE(-1), &[range], 0) }.as_bool() {
+            // Not available before Windows 8, touch the pages instead. Pages are at least 4KB.
+            debug!("PrefetchVirtualMemory failed, touching pages");
+            crate::touch_pages(addr, len, 4096);
+        }
+        Ok(())
+    }
+    pub fn lock_in_memory(&self) -> Result<(), ShmemError> {
+        trace!("VirtualLock({:p}, {})", self.view, self.map_size);
+        if unsafe { Memory::VirtualLock(self.as_mut_ptr() as _, self.map_size) }.as_bool() {
+            return Ok(());
+        }
+        let err_code = Error::from_win32().win32_error().unwrap();
+        Err(if err_code == ERROR_WORKING_SET_QUOTA {
+            ShmemError::MemoryLockLimit
+        } else {
+            ShmemError::UnknownOsError(err_code.0)
+        })
+    }
+    pub fn unlock_in_memory(&self) -> Result<(), ShmemError> {
+        trace!("VirtualUnlock({:p}, {})", self.view, self.map_size);
+        if unsafe { Memory::VirtualUnlock(self.as_mut_ptr() as _, self.map_size) }.as_bool() {
+            Ok(())
+        } else {
+            Err(ShmemError::UnknownOsError(
+                Error::from_win32().win32_error().unwrap().0,
+            ))
+        }
+    }
+}
+
+/// File link permissions are not configurable on Windows
+pub fn set_flink_permissions(_ext: &ShmemConfExt, _flink: &File) -> std::io::Result<()> {
+    Ok(())
 }
 
 /// Returns the path to a temporary directory in which to store files backing the shared memory. If it
@@ -131,12 +239,60 @@ fn get_tmp_dir() -> Result<PathBuf, ShmemError> {
     }
 }
 
+/// Rejects identifiers that can't name both the backing file and the mapping object
+///
+/// Names are converted to UTF-16 so any unicode character is fine, except for the ones reserved in file names
+/// (`\` is also the namespace separator of mapping objects). Raw mappings may use any mapping object name,
+/// like `Local\app:1`, they only get a backing file when the name is a valid file name.
+fn validate_os_id(unique_id: &str, ext: &ShmemConfExt) -> Result<(), ShmemError> {
+    let name = unique_id.trim_start_matches('/');
+    if name.is_empty()
+        || name.encode_utf16().count() > MAX_PATH as usize
+        || name.contains(char::is_control)
+        || (!ext.allow_raw && !is_file_name(name))
+    {
+        return Err(ShmemError::InvalidOsId);
+    }
+    Ok(())
+}
+
+/// Returns whether `name` has none of the characters reserved in file names
+fn is_file_name(name: &str) -> bool {
+    !name.contains(|c: char| "/\\:*?\"<>|".contains(c))
+}
+
+/// Returns whether the error means there is not enough memory, disk space or address space for the mapping
+fn is_out_of_memory(err_code: WIN32_ERROR) -> bool {
+    [
+        ERROR_NOT_ENOUGH_MEMORY,
+        ERROR_OUTOFMEMORY,
+        ERROR_COMMITMENT_LIMIT,
+        ERROR_DISK_FULL,
+    ]
+    .contains(&err_code)
+}
+
+/// Returns the name of the mapping object for `unique_id`
+fn map_name(unique_id: &str, ext: &ShmemConfExt) -> String {
+    if ext.global {
+        format!("Global\\{unique_id}")
+    } else {
+        unique_id.to_string()
+    }
+}
+
 fn new_map(
     unique_id: &str,
     mut map_size: usize,
     create: bool,
-    allow_raw: bool,
+    access: MapAccess,
+    addr: Option<usize>,
+    window: Option<(usize, usize)>,
+    ext: &ShmemConfExt,
 ) -> Result<MapData, ShmemError> {
+    validate_os_id(unique_id, ext)?;
+    let map_name = map_name(unique_id, ext);
+
     // Create file to back the shared memory
     let mut file_path = get_tmp_dir()?;
     file_path.push(unique_id.trim_start_matches('/'));
@@ -148,7 +304,7 @@ fn new_map(
 
     let mut opt = OpenOptions::new();
     opt.read(true)
-        .write(true)
+        .write(access == MapAccess::ReadWrite)
         .share_mode((FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE).0)
         .attributes((FILE_ATTRIBUTE_TEMPORARY).0);
     if create {
@@ -157,9 +313,25 @@ fn new_map(
         opt.create(false);
     };
 
+    // Raw mappings whose name isn't a valid file name can only be opened without a backing file
+    let file_res = if is_file_name(unique_id.trim_start_matches('/')) {
+        opt.open(&file_path)
+    } else if create {
+        return Err(ShmemError::InvalidOsId);
+    } else {
+        Err(std::io::Error::from(ErrorKind::NotFound))
+    };
+
+    // Size of the whole mapping, openers don't know it until they find the backing file
+    let mut object_size = map_size;
     let mut persistent_file = None;
-    let map_h = match opt.open(&file_path) {
+    let map_h = match file_res {
         Ok(f) => {
+            if !create {
+                if let Ok(m) = f.metadata() {
+                    object_size = m.len() as usize;
+                }
+            }
             //Create/open Mapping using persistent file
             debug!(
                 "{} memory mapping",
@@ -167,22 +339,27 @@ fn new_map(
             );
             let high_size: u32 = ((map_size as u64 & 0xFFFF_FFFF_0000_0000_u64) >> 32) as u32;
             let low_size: u32 = (map_size as u64 & 0xFFFF_FFFF_u64) as u32;
+            let protect = match access {
+                MapAccess::ReadWrite => PAGE_READWRITE,
+                MapAccess::ReadOnly => Memory::PAGE_READONLY,
+                MapAccess::CopyOnWrite => Memory::PAGE_WRITECOPY,
+            };
             trace!(
                 "CreateFileMapping({:?}, NULL, {:X}, {}, {}, '{}')",
                 HANDLE(f.as_raw_handle() as _),
-                PAGE_READWRITE.0,
+                protect.0,
                 high_size,
                 low_size,
-                unique_id,
+                map_name,
             );
 
             match CreateFileMapping(
                 HANDLE(f.as_raw_handle() as _),
                 None,
-                PAGE_READWRITE,
+                protect,
                 high_size,
                 low_size,
-                unique_id,
+                &map_name,
             ) {
                 Ok(v) => {
                     persistent_file = Some(f);
@@ -192,6 +369,10 @@ fn new_map(
                     let err_code = e.win32_error().unwrap();
                     return if err_code == ERROR_ALREADY_EXISTS {
                         Err(ShmemError::MappingIdExists)
+                    } else if err_code == ERROR_ACCESS_DENIED {
+                        Err(ShmemError::MapPermissionDenied)
+                    } else if is_out_of_memory(err_code) {
+                        Err(ShmemError::OutOfMemory(object_size))
                     } else {
                         Err(if create {
                             ShmemError::MapCreateFailed(err_code.0)
@@ -203,25 +384,51 @@ fn new_map(
             }
         }
         Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(ShmemError::MappingIdExists),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            return Err(ShmemError::MapPermissionDenied)
+        }
+        // The temporary directory itself can make the path too long
+        Err(e)
+            if [
+                ERROR_INVALID_NAME,
+                ERROR_FILENAME_EXCED_RANGE,
+                ERROR_BAD_PATHNAME,
+            ]
+            .iter()
+            .any(|c| e.raw_os_error() == Some(c.0 as _)) =>
+        {
+            return Err(ShmemError::InvalidOsId)
+        }
         Err(e) => {
             if create {
                 return Err(ShmemError::MapCreateFailed(e.raw_os_error().unwrap() as _));
-            } else if !allow_raw {
+            } else if !ext.allow_raw && !ext.global {
                 return Err(ShmemError::MapOpenFailed(ERROR_FILE_NOT_FOUND.0));
             }
 
-            // This may be a mapping that isnt managed by this crate
+            // This may be a mapping that isnt managed by this crate or a global mapping whose backing file
+            // lives in the temporary directory of another user
             // Try to open the mapping without any backing file
+            let map_access = if access == MapAccess::ReadWrite {
+                FILE_MAP_ALL_ACCESS
+            } else {
+                FILE_MAP_READ
+            };
             trace!(
                 "OpenFileMappingW({:?}, {}, '{}')",
-                FILE_MAP_ALL_ACCESS,
+                map_access,
                 false,
-                unique_id,
+                map_name
             );
-            match OpenFileMapping(FILE_MAP_ALL_ACCESS, false, unique_id) {
+            match OpenFileMapping(map_access, false, &map_name) {
                 Ok(h) => h,
                 Err(e) => {
-                    return Err(ShmemError::MapOpenFailed(e.win32_error().unwrap().0));
+                    let err_code = e.win32_error().unwrap();
+                    return Err(if err_code == ERROR_ACCESS_DENIED {
+                        ShmemError::MapPermissionDenied
+                    } else {
+                        ShmemError::MapOpenFailed(err_code.0)
+                    });
                 }
             }
         }
@@ -230,31 +437,73 @@ fn new_map(
 
     //Map mapping into address space
     debug!("Loading mapping into address space");
+    let view_access = match access {
+        MapAccess::ReadWrite => FILE_MAP_READ | FILE_MAP_WRITE,
+        MapAccess::ReadOnly => FILE_MAP_READ,
+        MapAccess::CopyOnWrite => Memory::FILE_MAP_COPY,
+    };
+    //Only map the requested window of the mapping
+    let (offset, view_size) = window.unwrap_or_default();
+    if offset % ALLOCATION_GRANULARITY != 0 {
+        return Err(ShmemError::UnalignedOffset(offset));
+    }
+    if let (Some(f), Some(_)) = (persistent_file.as_ref(), window) {
+        let file_size = match f.metadata() {
+            Ok(m) => m.len(),
+            Err(e) => return Err(ShmemError::MapOpenFailed(e.raw_os_error().unwrap_or(0) as _)),
+        };
+        match offset.checked_add(view_size) {
+            Some(end) if end as u64 <= file_size => {}
+            _ => return Err(ShmemError::RangeOutOfBounds),
+        }
+    }
+    let high_offset: u32 = ((offset as u64 & 0xFFFF_FFFF_0000_0000_u64) >> 32) as u32;
+    let low_offset: u32 = (offset as u64 & 0xFFFF_FFFF_u64) as u32;
+    let base_addr = addr.unwrap_or_default() as *const c_void;
     trace!(
-        "MapViewOfFile(0x{:X}, {:X}, 0, 0, 0)",
+        "MapViewOfFileEx(0x{:X}, {:X}, {}, {}, {}, {:p})",
         map_h,
-        (FILE_MAP_READ | FILE_MAP_WRITE).0,
+        view_access.0,
+        high_offset,
+        low_offset,
+        view_size,
+        base_addr
     );
-    let map_ptr = match MapViewOfFile(map_h.as_handle(), FILE_MAP_READ | FILE_MAP_WRITE, 0, 0, 0) {
-        Ok(v) => v,
-        Err(e) => {
-            return Err(if create {
-                ShmemError::MapCreateFailed(e.win32_error().unwrap().0)
-            } else {
-                ShmemError::MapOpenFailed(e.win32_error().unwrap().0)
-            })
-        }
+    let map_ptr = unsafe {
+        Memory::MapViewOfFileEx(
+            map_h.as_handle(),
+            view_access,
+            high_offset,
+            low_offset,
+            view_size,
+            base_addr,
+        )
     };
+    if map_ptr.is_null() {
+        let err_code = Error::from_win32().win32_error().unwrap();
+        return Err(if addr.is_some() && err_code == ERROR_INVALID_ADDRESS {
+            ShmemError::AddressUnavailable
+        } else if is_out_of_memory(err_code) {
+            ShmemError::OutOfMemory(window.map_or(object_size, |(_, len)| len))
+        } else if create {
+            ShmemError::MapCreateFailed(err_code.0)
+        } else {
+            ShmemError::MapOpenFailed(err_code.0)
+        });
+    }
+    let map_ptr = MapView(map_ptr);
     trace!("\t{:p}", map_ptr);
 
-    if !create {
-        //Get the real size of the openned mapping
-        let mut info = MEMORY_BASIC_INFORMATION::default();
-        if let Err(e) = VirtualQuery(map_ptr.as_mut_ptr(), &mut info) {
-            return Err(ShmemError::UnknownOsError(e.win32_error().unwrap().0));
-        }
-        map_size = info.RegionSize;
+    //Get the real size of the mapping. Views are rounded up to the page size so query it even when
+    //creating, this way the creator and openers always agree on the size
+    let mut info = MEMORY_BASIC_INFORMATION::default();
+    if let Err(e) = VirtualQuery(map_ptr.as_mut_ptr(), &mut info) {
+        return Err(ShmemError::UnknownOsError(e.win32_error().unwrap().0));
     }
+    map_size = match window {
+        Some((_, len)) => len,
+        None => info.RegionSize,
+    };
 
     Ok(MapData {
         owner: create,
@@ -267,15 +516,91 @@ fn new_map(
 }
 
 //Creates a mapping specified by the uid and size
-pub fn create_mapping(unique_id: &str, map_size: usize) -> Result<MapData, ShmemError> {
-    new_map(unique_id, map_size, true, false)
+pub fn create_mapping(
+    unique_id: &str,
+    map_size: usize,
+    addr: Option<usize>,
+    ext: &ShmemConfExt,
+) -> Result<MapData, ShmemError> {
+    new_map(
+        unique_id,
+        map_size,
+        true,
+        MapAccess::ReadWrite,
+        addr,
+        None,
+        ext,
+    )
 }
 
 //Opens an existing mapping specified by its uid
 pub fn open_mapping(
     unique_id: &str,
     map_size: usize,
+    access: MapAccess,
+    addr: Option<usize>,
+    window: Option<(usize, usize)>,
     ext: &ShmemConfExt,
 ) -> Result<MapData, ShmemError> {
-    new_map(unique_id, map_size, false, ext.allow_raw)
+    new_map(unique_id, map_size, false, access, addr, window, ext)
+}
+
+//Maps the object behind an existing mapping a second time
+pub fn clone_mapping(
+    map: &MapData,
+    access: MapAccess,
+    window: Option<(usize, usize)>,
+    ext: &ShmemConfExt,
+) -> Result<MapData, ShmemError> {
+    new_map(
+        &map.unique_id,
+        map.map_size,
+        false,
+        access,
+        None,
+        window,
+        ext,
+    )
+}
+
+//Windows mappings always have a name
+pub fn is_anonymous(_ext: &ShmemConfExt) -> bool {
+    false
+}
+
+//Returns whether a mapping object named after the uid exists
+pub fn mapping_exists(unique_id: &str, ext: &ShmemConfExt) -> Result<bool, ShmemError> {
+    validate_os_id(unique_id, ext)?;
+
+    // The backing file keeps the mapping alive for openers even once every handle to it is closed
+    if !ext.allow_raw && !ext.global {
+        let mut file_path = get_tmp_dir()?;
+        file_path.push(unique_id.trim_start_matches('/'));
+        trace!("{} is_file()", file_path.to_string_lossy());
+        if file_path.is_file() {
+            return Ok(true);
+        }
+    }
+
+    let map_name = map_name(unique_id, ext);
+    trace!(
+        "OpenFileMappingW({:?}, {}, '{}')",
+        FILE_MAP_READ,
+        false,
+        map_name
+    );
+    match OpenFileMapping(FILE_MAP_READ, false, &map_name) {
+        Ok(_) => Ok(true),
+        Err(e) => {
+            let err_code = e.win32_error().unwrap();
+            if err_code == ERROR_FILE_NOT_FOUND {
+                Ok(false)
+            } else if err_code == ERROR_ACCESS_DENIED {
+                // Someone else's mapping
+                Ok(true)
+            } else {
+                Err(ShmemError::UnknownOsError(err_code.0))
+            }
+        }
+    }
 }
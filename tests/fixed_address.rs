@@ -0,0 +1,28 @@
+use shared_memory::{ShmemConf, ShmemError};
+
+// Kept in its own test binary : other tests mapping memory in parallel could take the address between
+// finding it and mapping it again
+#[test]
+fn fixed_address() {
+    // Find an address that is free, as far as we know
+    let addr = {
+        let s = ShmemConf::new().size(4096).create().unwrap();
+        s.as_ptr() as usize
+    };
+
+    let s1 = ShmemConf::new()
+        .size(4096)
+        .fixed_address(addr)
+        .create()
+        .unwrap();
+    assert_eq!(s1.as_ptr() as usize, addr);
+
+    // The address is now used by s1
+    assert!(matches!(
+        ShmemConf::new()
+            .os_id(s1.get_os_id())
+            .fixed_address(addr)
+            .open(),
+        Err(ShmemError::AddressUnavailable)
+    ));
+}
@@ -1,6 +1,7 @@
 use std::path::Path;
+use std::time::Duration;
 
-use shared_memory::ShmemConf;
+use shared_memory::{ShmemConf, ShmemError, ShmemStatus};
 
 #[test]
 fn create_new() {
@@ -63,6 +64,17 @@ fn open_os_id() {
     drop(s2);
 }
 
+#[test]
+fn open_size_matches_create() {
+    // Deliberately not a multiple of the page size
+    let s1 = ShmemConf::new().size(4090).create().unwrap();
+    let s2 = ShmemConf::new().os_id(s1.get_os_id()).open().unwrap();
+
+    assert_eq!(s1.len(), s2.len());
+    // Every page size is a multiple of 4KB
+    assert_eq!(s1.len() % 4096, 0);
+}
+
 #[test]
 fn open_flink() {
     let flink = Path::new("create_new2");
@@ -90,6 +102,52 @@ fn open_flink() {
     drop(s2);
 }
 
+#[test]
+fn flink_relative_path() {
+    let flink = Path::new("create_new3");
+    let s1 = ShmemConf::new().flink(flink).size(4090).create().unwrap();
+
+    // The relative path was resolved against the current directory
+    let abs_flink = std::env::current_dir().unwrap().join(flink);
+    assert_eq!(s1.get_flink_path(), Some(&abs_flink));
+
+    // Relative and absolute paths point to the same mapping
+    let s2 = ShmemConf::new().flink(&abs_flink).open().unwrap();
+    assert_eq!(s1.get_os_id(), s2.get_os_id());
+
+    // Missing links report the path they were looked up at
+    match ShmemConf::new().flink("create_new3_missing").open() {
+        Err(ShmemError::LinkDoesNotExist(p)) => {
+            assert_eq!(p, abs_flink.with_file_name("create_new3_missing"))
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn flink_empty_path() {
+    for flink in ["", "  "] {
+        assert!(matches!(
+            ShmemConf::new().flink(flink).size(4090).create(),
+            Err(ShmemError::LinkPathEmpty)
+        ));
+        assert!(matches!(
+            ShmemConf::new().flink(flink).open(),
+            Err(ShmemError::LinkPathEmpty)
+        ));
+        let conf = ShmemConf::new().flink(flink);
+        assert!(matches!(conf.status(), Err(ShmemError::LinkPathEmpty)));
+        assert!(matches!(
+            conf.wait_until_removed(Duration::ZERO),
+            Err(ShmemError::LinkPathEmpty)
+        ));
+        assert!(matches!(
+            conf.read_flink_metadata(),
+            Err(ShmemError::LinkPathEmpty)
+        ));
+    }
+}
+
 #[test]
 fn share_data() {
     let s1 = ShmemConf::new()
@@ -115,3 +173,497 @@ fn share_data() {
         assert_eq!(read_val, shared_val);
     }
 }
+
+#[test]
+fn flush() {
+    let s = ShmemConf::new().size(4096 * 3).create().unwrap();
+
+    assert!(s.flush().is_ok());
+    // Unaligned ranges are extended to page boundaries
+    assert!(s.flush_range(4100, 10).is_ok());
+    assert!(s.flush_range(s.len(), 0).is_ok());
+
+    assert!(s.flush_range(s.len(), 1).is_err());
+    assert!(s.flush_range(usize::MAX, 2).is_err());
+}
+
+#[test]
+fn lock_in_memory() {
+    let s = ShmemConf::new().size(4096).create().unwrap();
+
+    s.lock_in_memory().unwrap();
+    s.unlock_in_memory().unwrap();
+}
+
+#[test]
+fn create_is_zeroed() {
+    let s = ShmemConf::new().size(4096).create().unwrap();
+
+    assert!(unsafe { s.as_slice() }.iter().all(|b| *b == 0));
+}
+
+#[test]
+fn open_read_only() {
+    let s1 = ShmemConf::new().size(4096).create().unwrap();
+    unsafe { *s1.as_ptr() = 42 };
+
+    let s2 = ShmemConf::new()
+        .os_id(s1.get_os_id())
+        .read_only(true)
+        .open()
+        .unwrap();
+
+    assert!(s2.is_read_only());
+    assert!(!s1.is_read_only());
+    assert_eq!(unsafe { *s2.as_ptr() }, 42);
+}
+
+#[test]
+fn ptr_range() {
+    let s = ShmemConf::new().size(4096).create().unwrap();
+    let range = s.as_ptr_range();
+
+    assert_eq!(range.start, s.as_ptr());
+    assert_eq!(range.end as usize - range.start as usize, s.len());
+}
+
+#[test]
+fn close() {
+    let flink = Path::new("close_flink");
+    let s = ShmemConf::new().size(4096).flink(flink).create().unwrap();
+    let os_id = s.get_os_id().to_string();
+
+    s.close().unwrap();
+
+    assert!(!flink.is_file());
+    assert!(ShmemConf::new().os_id(os_id).open().is_err());
+}
+
+#[test]
+fn flink_overwrite() {
+    let flink = Path::new("overwrite_flink");
+    let s1 = ShmemConf::new().size(4096).flink(flink).create().unwrap();
+
+    assert!(matches!(
+        ShmemConf::new().size(4096).flink(flink).create(),
+        Err(ShmemError::LinkExists)
+    ));
+
+    let s2 = ShmemConf::new()
+        .size(4096)
+        .flink(flink)
+        .force_create_flink()
+        .create()
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(flink).unwrap(), s2.get_os_id());
+
+    // No temporary file is left next to the link
+    let leftovers = std::fs::read_dir(".")
+        .unwrap()
+        .filter(|e| {
+            let name = e.as_ref().unwrap().file_name();
+            name.to_string_lossy().starts_with(".overwrite_flink")
+        })
+        .count();
+    assert_eq!(leftovers, 0);
+
+    drop(s1);
+    drop(s2);
+}
+
+#[test]
+fn try_clone() {
+    let s1 = ShmemConf::new().size(4096).create().unwrap();
+    let s2 = s1.try_clone().unwrap();
+
+    assert!(!s2.is_owner());
+    assert_eq!(s1.get_os_id(), s2.get_os_id());
+    assert_eq!(s1.len(), s2.len());
+    assert_ne!(s1.as_ptr(), s2.as_ptr());
+
+    unsafe { *s1.as_ptr() = 42 };
+    assert_eq!(unsafe { *s2.as_ptr() }, 42);
+
+    // Dropping the clone leaves the mapping alone
+    drop(s2);
+    assert!(ShmemConf::new().os_id(s1.get_os_id()).open().is_ok());
+
+    // A read-only view sees the writes of the writable one
+    let ro = s1.try_clone_read_only().unwrap();
+    assert!(ro.is_read_only() && !s1.is_read_only());
+    unsafe { *s1.as_ptr() = 43 };
+    assert_eq!(unsafe { *ro.as_ptr() }, 43);
+}
+
+#[test]
+fn open_copy_on_write() {
+    let s1 = ShmemConf::new().size(4096).create().unwrap();
+    unsafe { *s1.as_ptr() = 42 };
+
+    let s2 = ShmemConf::new()
+        .os_id(s1.get_os_id())
+        .copy_on_write(true)
+        .open()
+        .unwrap();
+    assert!(s2.is_copy_on_write());
+    assert_eq!(unsafe { *s2.as_ptr() }, 42);
+
+    // Private writes are never seen by the shared memory
+    unsafe { *s2.as_ptr() = 1 };
+    assert_eq!(unsafe { *s1.as_ptr() }, 42);
+    assert_eq!(unsafe { *s2.as_ptr() }, 1);
+}
+
+#[test]
+fn flink_trailing_whitespace() {
+    let flink = Path::new("whitespace_flink");
+    let s1 = ShmemConf::new().size(4096).create().unwrap();
+
+    std::fs::write(flink, format!("{}\r\n\0", s1.get_os_id())).unwrap();
+    let s2 = ShmemConf::new().flink(flink).open();
+
+    std::fs::write(flink, "\n").unwrap();
+    let empty = ShmemConf::new().flink(flink).open();
+
+    std::fs::remove_file(flink).unwrap();
+    assert_eq!(s2.unwrap().get_os_id(), s1.get_os_id());
+    assert!(matches!(empty, Err(ShmemError::LinkCorrupt)));
+}
+
+#[test]
+fn debug_format() {
+    let s = ShmemConf::new().size(4096).create().unwrap();
+    let debug = format!("{:?}", s);
+
+    assert!(debug.contains(s.get_os_id()));
+    assert!(debug.contains("owner: true"));
+    assert!(format!("{:?}", ShmemConf::new().size(4096)).contains("size: 4096"));
+}
+
+#[test]
+fn status() {
+    let flink = Path::new("status_flink");
+    let conf = ShmemConf::new().flink(flink);
+    assert_eq!(conf.status().unwrap(), ShmemStatus::Missing);
+
+    let s = ShmemConf::new().size(4096).flink(flink).create().unwrap();
+    let os_id = s.get_os_id().to_string();
+    assert_eq!(conf.status().unwrap(), ShmemStatus::Live);
+    assert_eq!(
+        ShmemConf::new().os_id(&os_id).status().unwrap(),
+        ShmemStatus::Live
+    );
+
+    // Leave the flink behind
+    std::fs::copy(flink, "status_flink_copy").unwrap();
+    drop(s);
+    std::fs::rename("status_flink_copy", flink).unwrap();
+
+    let stale = conf.status();
+    std::fs::remove_file(flink).unwrap();
+    assert_eq!(stale.unwrap(), ShmemStatus::Stale);
+    assert_eq!(
+        ShmemConf::new().os_id(&os_id).status().unwrap(),
+        ShmemStatus::Missing
+    );
+}
+
+#[test]
+fn os_id_unicode() {
+    let os_id = format!("/shmem_é_{}", std::process::id());
+    let s1 = ShmemConf::new().size(4096).os_id(&os_id).create().unwrap();
+    let s2 = ShmemConf::new().os_id(&os_id).open().unwrap();
+
+    assert_eq!(s1.get_os_id(), s2.get_os_id());
+}
+
+#[cfg(windows)]
+#[test]
+fn os_id_reserved_chars() {
+    assert!(matches!(
+        ShmemConf::new().size(4096).os_id("/a\\b").create(),
+        Err(ShmemError::InvalidOsId)
+    ));
+    assert!(matches!(
+        ShmemConf::new().size(4096).os_id("/a:b").create(),
+        Err(ShmemError::InvalidOsId)
+    ));
+}
+
+#[test]
+fn wipe_on_drop() {
+    let s1 = ShmemConf::new()
+        .size(4096)
+        .wipe_on_drop(true)
+        .create()
+        .unwrap();
+    let mut s2 = ShmemConf::new().os_id(s1.get_os_id()).open().unwrap();
+    unsafe { s2.as_slice_mut().fill(0xAA) };
+
+    drop(s1);
+
+    assert!(unsafe { s2.as_slice() }.iter().all(|b| *b == 0));
+}
+
+#[test]
+fn open_retries() {
+    let flink = Path::new("retries_flink");
+
+    // Create the mapping while the opener is already waiting for it
+    let creator = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        let s = ShmemConf::new().size(4096).flink(flink).create().unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        drop(s);
+    });
+
+    let s = ShmemConf::new()
+        .flink(flink)
+        .open_retries(50, Duration::from_millis(20))
+        .open();
+    assert!(s.is_ok());
+
+    drop(s);
+    creator.join().unwrap();
+
+    // Give up once the retries are exhausted
+    assert!(matches!(
+        ShmemConf::new()
+            .flink(flink)
+            .open_retries(2, Duration::from_millis(1))
+            .open(),
+        Err(ShmemError::LinkDoesNotExist(_))
+    ));
+}
+
+#[test]
+fn prefault() {
+    let s = ShmemConf::new()
+        .size(4 * 4096)
+        .prefault(true)
+        .create()
+        .unwrap();
+
+    s.prefault_range(4096, 100).unwrap();
+    assert!(matches!(
+        s.prefault_range(1, s.len()),
+        Err(ShmemError::RangeOutOfBounds)
+    ));
+}
+
+#[test]
+fn size_overflow() {
+    assert!(matches!(
+        ShmemConf::new().size(usize::MAX).create(),
+        Err(ShmemError::SizeOverflow(usize::MAX))
+    ));
+    assert!(matches!(
+        ShmemConf::new().size(0).create(),
+        Err(ShmemError::MapSizeZero)
+    ));
+}
+
+#[test]
+fn window() {
+    // Offsets must be aligned to the allocation granularity on Windows
+    let granularity = 64 * 1024;
+    let s = ShmemConf::new().size(4 * granularity).create().unwrap();
+    unsafe { *s.as_ptr().add(granularity) = 0xAA };
+
+    let w = ShmemConf::new()
+        .os_id(s.get_os_id())
+        .window(granularity, granularity)
+        .open()
+        .unwrap();
+    assert_eq!(w.len(), granularity);
+    assert_eq!(unsafe { *w.as_ptr() }, 0xAA);
+    assert_eq!(unsafe { *w.try_clone().unwrap().as_ptr() }, 0xAA);
+
+    assert!(matches!(
+        ShmemConf::new()
+            .os_id(s.get_os_id())
+            .window(granularity, 4 * granularity)
+            .open(),
+        Err(ShmemError::RangeOutOfBounds)
+    ));
+    assert!(matches!(
+        ShmemConf::new()
+            .os_id(s.get_os_id())
+            .window(100, granularity)
+            .open(),
+        Err(ShmemError::UnalignedOffset(100))
+    ));
+    assert!(matches!(
+        ShmemConf::new()
+            .os_id(s.get_os_id())
+            .window(granularity, 0)
+            .open(),
+        Err(ShmemError::MapSizeZero)
+    ));
+}
+
+#[test]
+fn on_unmap() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let mut s = ShmemConf::new().size(4096).create().unwrap();
+    for i in 0..3 {
+        let calls = calls.clone();
+        s.on_unmap(move || {
+            calls.borrow_mut().push(i);
+            if i == 1 {
+                panic!("on_unmap callback panicked");
+            }
+        });
+    }
+    drop(s);
+
+    assert_eq!(*calls.borrow(), vec![2, 1, 0]);
+}
+
+#[test]
+fn wait_until_removed() {
+    let flink = Path::new("wait_until_removed_flink");
+    let conf = ShmemConf::new().flink(flink);
+    let s = ShmemConf::new().size(4096).flink(flink).create().unwrap();
+
+    assert!(matches!(
+        conf.wait_until_removed(Duration::from_millis(20)),
+        Err(ShmemError::Timeout)
+    ));
+
+    let os_id = s.get_os_id().to_string();
+    let waiter = std::thread::spawn(move || conf.wait_until_removed(Duration::from_secs(10)));
+    std::thread::sleep(Duration::from_millis(50));
+    drop(s);
+    waiter.join().unwrap().unwrap();
+    assert!(ShmemConf::new().os_id(&os_id).open().is_err());
+
+    // The mapping outlives its deleted flink
+    let s = ShmemConf::new().size(4096).flink(flink).create().unwrap();
+    let conf = ShmemConf::new().flink(flink).os_id(s.get_os_id());
+    std::fs::remove_file(flink).unwrap();
+    assert_eq!(conf.status().unwrap(), ShmemStatus::Live);
+    assert!(matches!(
+        conf.wait_until_removed(Duration::from_millis(50)),
+        Err(ShmemError::Timeout)
+    ));
+}
+
+#[test]
+fn flink_metadata() {
+    let flink = Path::new("metadata_flink");
+    let s1 = ShmemConf::new()
+        .size(4096)
+        .flink(flink)
+        .flink_metadata("schema", "3")
+        .flink_metadata("label", "frame buffer")
+        .flink_metadata("padded", "v  ")
+        .create()
+        .unwrap();
+
+    let conf = ShmemConf::new().flink(flink);
+    let metadata = conf.read_flink_metadata().unwrap();
+    assert_eq!(metadata.len(), 3);
+    assert_eq!(metadata["schema"], "3");
+    assert_eq!(metadata["label"], "frame buffer");
+    assert_eq!(metadata["padded"], "v  ");
+    assert_eq!(conf.open().unwrap().get_os_id(), s1.get_os_id());
+
+    assert!(matches!(
+        ShmemConf::new()
+            .size(4096)
+            .flink("metadata_flink_invalid")
+            .flink_metadata("a=b", "c")
+            .create(),
+        Err(ShmemError::LinkMetadataInvalid)
+    ));
+
+    // Links holding only the os_id have no metadata
+    let s2 = ShmemConf::new()
+        .size(4096)
+        .flink("metadata_flink_bare")
+        .create()
+        .unwrap();
+    let bare = ShmemConf::new().flink("metadata_flink_bare");
+    assert!(bare.read_flink_metadata().unwrap().is_empty());
+    drop(s2);
+
+    let malformed = Path::new("metadata_flink_malformed");
+    std::fs::write(malformed, format!("{}\nnot a pair\n", s1.get_os_id())).unwrap();
+    let result = ShmemConf::new().flink(malformed).read_flink_metadata();
+    std::fs::remove_file(malformed).unwrap();
+    assert!(matches!(result, Err(ShmemError::LinkMetadataCorrupt)));
+}
+
+#[test]
+fn open_min_size() {
+    let s = ShmemConf::new().size(4096).create().unwrap();
+    let len = s.len();
+
+    assert!(ShmemConf::new()
+        .os_id(s.get_os_id())
+        .min_size(len)
+        .open()
+        .is_ok());
+    match ShmemConf::new()
+        .os_id(s.get_os_id())
+        .min_size(len + 1)
+        .open()
+    {
+        Err(ShmemError::SizeTooSmall(size)) => assert_eq!(size, len),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn replace_stale_flink() {
+    let flink = Path::new("replace_stale_flink");
+    let s1 = ShmemConf::new().size(4096).flink(flink).create().unwrap();
+
+    // Live links are never replaced
+    assert!(matches!(
+        ShmemConf::new()
+            .size(4096)
+            .flink(flink)
+            .replace_stale_flink(true)
+            .create(),
+        Err(ShmemError::LinkExists)
+    ));
+
+    // Leave the flink behind
+    std::fs::copy(flink, "replace_stale_flink_copy").unwrap();
+    drop(s1);
+    std::fs::rename("replace_stale_flink_copy", flink).unwrap();
+
+    assert!(matches!(
+        ShmemConf::new().size(4096).flink(flink).create(),
+        Err(ShmemError::LinkExists)
+    ));
+    let s2 = ShmemConf::new()
+        .size(4096)
+        .flink(flink)
+        .replace_stale_flink(true)
+        .create()
+        .unwrap();
+    assert_eq!(
+        ShmemConf::new().flink(flink).open().unwrap().get_os_id(),
+        s2.get_os_id()
+    );
+    drop(s2);
+
+    // A link that stays empty is stale too
+    std::fs::write(flink, "").unwrap();
+    let s3 = ShmemConf::new()
+        .size(4096)
+        .flink(flink)
+        .replace_stale_flink(true)
+        .create()
+        .unwrap();
+    assert_eq!(
+        ShmemConf::new().flink(flink).open().unwrap().get_os_id(),
+        s3.get_os_id()
+    );
+}
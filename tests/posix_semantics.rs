@@ -88,3 +88,209 @@ fn posix_behavior() {
     thread_b.join().unwrap();
     thread_c.join().unwrap();
 }
+
+#[cfg(target_os = "linux")]
+#[test]
+fn madvise_hints() {
+    let shmem = ShmemConf::new()
+        .size(4096)
+        .dont_dump(true)
+        .dont_fork(true)
+        .huge_pages(true)
+        .create()
+        .unwrap();
+    let _shmem2 = ShmemConf::new()
+        .os_id(shmem.get_os_id())
+        .dont_dump(true)
+        .open()
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn fork_child_releases_ownership() {
+    let mut shmem = ShmemConf::new().size(4096).create().unwrap();
+    let os_id = String::from(shmem.get_os_id());
+
+    match unsafe { libc::fork() } {
+        0 => {
+            // Child : drop its inherited copy without deleting the mapping
+            shmem.set_owner(false);
+            unsafe { shmem.as_slice_mut()[0] = 42 };
+            drop(shmem);
+            unsafe { libc::_exit(0) };
+        }
+        pid => {
+            assert!(pid > 0);
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+            // The mapping survived the child and contains what it wrote
+            let shmem2 = ShmemConf::new().os_id(&os_id).open().unwrap();
+            assert_eq!(unsafe { shmem2.as_slice()[0] }, 42);
+            assert!(shmem.is_owner());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn open_permission_denied() {
+    use shared_memory::ShmemError;
+    use std::os::unix::fs::PermissionsExt;
+
+    // Root bypasses file permissions
+    if unsafe { libc::geteuid() } == 0 {
+        return;
+    }
+
+    let shmem = ShmemConf::new().size(4096).create().unwrap();
+    let shm_path = std::path::Path::new("/dev/shm").join(shmem.get_os_id().trim_start_matches('/'));
+    std::fs::set_permissions(shm_path, std::fs::Permissions::from_mode(0o400)).unwrap();
+
+    assert!(matches!(
+        ShmemConf::new().os_id(shmem.get_os_id()).open(),
+        Err(ShmemError::MapPermissionDenied)
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn anonymous_fd() {
+    use std::os::unix::io::{AsRawFd, BorrowedFd};
+
+    let s1 = ShmemConf::new()
+        .size(4096)
+        .anonymous(true)
+        .create()
+        .unwrap();
+
+    // The object does not have a name anymore
+    assert!(ShmemConf::new().os_id(s1.get_os_id()).open().is_err());
+
+    // Share it through a duplicated descriptor, like a child process inheriting it would
+    let fd = unsafe { BorrowedFd::borrow_raw(s1.as_raw_fd()) }
+        .try_clone_to_owned()
+        .unwrap();
+    let s2 = ShmemConf::new().open_fd(fd).unwrap();
+
+    assert!(!s2.is_owner());
+    assert!(s2.get_os_id().is_empty());
+    assert_eq!(s1.len(), s2.len());
+    unsafe {
+        s1.as_ptr().write_volatile(0xAB);
+        assert_eq!(s2.as_ptr().read_volatile(), 0xAB);
+    }
+
+    // There is no name to write in a file link
+    assert!(matches!(
+        ShmemConf::new()
+            .size(4096)
+            .anonymous(true)
+            .flink("anonymous_flink")
+            .create(),
+        Err(shared_memory::ShmemError::AnonymousFlink)
+    ));
+    assert!(!std::path::Path::new("anonymous_flink").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn os_id_too_long() {
+    use shared_memory::ShmemError;
+
+    let os_id = format!("/{}", "a".repeat(300));
+    assert!(matches!(
+        ShmemConf::new().size(4096).os_id(&os_id).create(),
+        Err(ShmemError::InvalidOsId)
+    ));
+    assert!(matches!(
+        ShmemConf::new().os_id(&os_id).open(),
+        Err(ShmemError::InvalidOsId)
+    ));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let flink = std::path::Path::new("permissions_flink");
+    let shmem = ShmemConf::new()
+        .size(4096)
+        .flink(flink)
+        .permissions(0o640)
+        .create()
+        .unwrap();
+
+    let shm_path = std::path::Path::new("/dev/shm").join(shmem.get_os_id().trim_start_matches('/'));
+    let shm_mode = std::fs::metadata(shm_path).unwrap().permissions().mode();
+    assert_eq!(shm_mode & 0o777, 0o640);
+
+    let flink_mode = std::fs::metadata(flink).unwrap().permissions().mode();
+    assert_eq!(flink_mode & 0o777, 0o640);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn out_of_memory() {
+    use shared_memory::ShmemError;
+
+    // Larger than the 47 bits of address space available to user processes
+    let size = 1 << 50;
+
+    let res = ShmemConf::new().size(size).create();
+    assert!(matches!(res, Err(ShmemError::OutOfMemory(s)) if s == size));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn numa_node() {
+    use shared_memory::ShmemError;
+
+    assert!(matches!(
+        ShmemConf::new()
+            .size(4096)
+            .numa_node(4_000_000_000)
+            .create(),
+        Err(ShmemError::NumaNodeInvalid(4_000_000_000))
+    ));
+
+    // Every NUMA system has a node 0
+    let shmem = match ShmemConf::new().size(4 * 4096).numa_node(0).create() {
+        Ok(s) => s,
+        // No NUMA support in the kernel or mbind() blocked by seccomp
+        Err(ShmemError::NumaUnsupported) => return,
+        Err(e) => panic!("{e}"),
+    };
+    assert_eq!(shmem.numa_node_of(3 * 4096).unwrap(), 0);
+    assert!(matches!(
+        shmem.numa_node_of(shmem.len()),
+        Err(ShmemError::RangeOutOfBounds)
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn resident_bytes() {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+    let shmem = ShmemConf::new().size(16 * page_size).create().unwrap();
+    let before = shmem.resident_bytes().unwrap();
+
+    for i in 0..4 {
+        unsafe { *shmem.as_ptr().add(i * page_size) = 1 };
+    }
+    assert!(shmem.resident_bytes().unwrap() >= before + 4 * page_size);
+    assert!(shmem.resident_bytes().unwrap() <= shmem.len());
+}
+
+#[cfg(unix)]
+#[test]
+fn as_iovec() {
+    let shmem = ShmemConf::new().size(4096).create().unwrap();
+    let iov = shmem.as_iovec();
+
+    assert_eq!(iov.iov_base as *const u8, shmem.as_ptr() as *const u8);
+    assert_eq!(iov.iov_len, shmem.len());
+}